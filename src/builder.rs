@@ -1,9 +1,13 @@
 use crate::{
 	bail_let,
 	cli::{OutputFormat, SkipPhases},
-	config::{Manifest, Script},
+	config::{
+		BootEntry, DiskFormat, DiskLabel, DiskSize, GrubTheme, ImageCompression, Manifest,
+		MountEntry, MountSource, OciConfig, PartitionLayout, PartitionType, Script,
+		SecureBootConfig, TarCompression, UkiTool,
+	},
 	feature_flag_bool, feature_flag_str,
-	util::{just_write, loopdev_with_file},
+	util::{just_write, loopdev_with_file, WipePolicy},
 };
 use cmd_lib::{run_cmd, run_fun};
 use color_eyre::{eyre::bail, Result};
@@ -22,6 +26,22 @@ const BOOTIMGS: &str = "boot_imgs";
 crate::prepend_comment!(GRUB_PREPEND_COMMENT: "/boot/grub/grub.cfg", "Grub configurations", katsu::builder::Bootloader::cp_grub);
 crate::prepend_comment!(LIMINE_PREPEND_COMMENT: "/boot/limine.cfg", "Limine configurations", katsu::builder::Bootloader::cp_limine);
 crate::prepend_comment!(REFIND_PREPEND_COMMENT: "/boot/efi/EFI/refind/refind.conf", "rEFInd configurations", katsu::builder::Bootloader::cp_refind);
+crate::prepend_comment!(SYSTEMD_BOOT_PREPEND_COMMENT: "/loader", "systemd-boot configurations", katsu::builder::Bootloader::cp_systemd_boot);
+
+crate::prepend_comment!(RPI_PREPEND_COMMENT: "/boot/efi/config.txt", "Raspberry Pi firmware configurations", katsu::builder::Bootloader::cp_raspberrypi_disk);
+crate::prepend_comment!(EXTLINUX_PREPEND_COMMENT: "/boot/extlinux/extlinux.conf", "U-Boot configurations", katsu::builder::Bootloader::cp_uboot_disk);
+
+/// Boot entries for installed disks, as `(title, extra cmdline)`
+const DISK_ENTRIES: &[(&str, &str)] = &[("{distro}", ""), ("{distro} (nomodeset)", "nomodeset")];
+
+/// [`DISK_ENTRIES`] with the distro name filled in
+fn disk_entries(manifest: &Manifest) -> Vec<BootEntry> {
+	let distro = manifest.distro.as_deref().unwrap_or("Linux");
+	DISK_ENTRIES
+		.iter()
+		.map(|(title, cmdline)| BootEntry::new(&title.replace("{distro}", distro), cmdline))
+		.collect()
+}
 
 /// Represents the bootloader types supported by Katsu
 ///
@@ -35,12 +55,18 @@ pub enum Bootloader {
 	Grub,
 	/// GRUB2 bootloader configured for legacy BIOS systems
 	GrubBios,
+	/// GRUB2 installed for both UEFI and legacy BIOS on disk images, the same as `Grub` for ISOs
+	GrubHybrid,
 	/// Limine bootloader, a modern UEFI/BIOS bootloader
 	Limine,
 	/// systemd-boot, a simple UEFI boot manager
 	SystemdBoot,
 	/// rEFInd, a graphical UEFI boot manager
 	REFInd,
+	/// U-Boot with an extlinux.conf, for ARM boards (disk images only)
+	UBoot,
+	/// Raspberry Pi firmware loading the kernel from an MBR FAT partition (disk images only)
+	RaspberryPi,
 }
 
 impl From<&str> for Bootloader {
@@ -49,8 +75,11 @@ impl From<&str> for Bootloader {
 			"limine" => Self::Limine,
 			"grub" | "grub2" => Self::Grub,
 			"grub-bios" => Self::GrubBios,
+			"grub-hybrid" => Self::GrubHybrid,
 			"systemd-boot" => Self::SystemdBoot,
 			"refind" => Self::REFInd,
+			"uboot" | "u-boot" | "extlinux" => Self::UBoot,
+			"raspberrypi" | "rpi" => Self::RaspberryPi,
 			_ => {
 				warn!("Unknown bootloader: {value}, falling back to GRUB");
 				Self::Grub
@@ -75,13 +104,15 @@ impl Bootloader {
 	/// * `Result<()>` - Success or failure with error details
 	pub fn install(&self, image: &Path) -> Result<()> {
 		match *self {
-			Self::Grub => info!("GRUB is not required to be installed to image, skipping"),
-			Self::Limine => cmd_lib::run_cmd!(limine bios-install $image 2>&1)?,
-			Self::SystemdBoot => cmd_lib::run_cmd!(bootctl --image=$image install 2>&1)?,
-			Self::GrubBios => {
-				cmd_lib::run_cmd!(grub-install --target=i386-pc --boot-directory=$image/boot 2>&1)?
+			Self::Grub | Self::GrubHybrid => {
+				info!("GRUB is not required to be installed to image, skipping")
 			},
+			Self::Limine => cmd_lib::run_cmd!(limine bios-install $image 2>&1)?,
+			Self::SystemdBoot => info!("systemd-boot doesn't need installation to ISO image, files already copied during ISO creation"),
+			Self::GrubBios => info!("GRUB BIOS is booted through El Torito, already set up during ISO creation"),
 			Self::REFInd => info!("rEFInd doesn't need installation to ISO image, files already copied during ISO creation"),
+			Self::UBoot => bail!("U-Boot is only supported for disk images"),
+			Self::RaspberryPi => bail!("Raspberry Pi firmware is only supported for disk images"),
 		}
 		Ok(())
 	}
@@ -98,11 +129,12 @@ impl Bootloader {
 	///   * Second element: Path to the BIOS bootloader binary
 	pub fn get_bins(&self) -> (&'static str, &'static str) {
 		match *self {
-			Self::Grub => ("boot/efi/EFI/fedora/shim.efi", "boot/eltorito.img"),
+			Self::Grub | Self::GrubHybrid => ("boot/efi/EFI/fedora/shim.efi", "boot/eltorito.img"),
 			Self::Limine => ("boot/limine-uefi-cd.bin", "boot/limine-bios-cd.bin"),
-			Self::GrubBios => todo!(),
-			Self::SystemdBoot => todo!(),
+			Self::GrubBios => ("", "boot/eltorito.img"),
+			Self::SystemdBoot => ("boot/efiboot.img", ""),
 			Self::REFInd => ("boot/efi/EFI/refind/refind_x64.efi", ""),
+			Self::UBoot | Self::RaspberryPi => ("", ""),
 		}
 	}
 	/// Copies vmlinuz and initramfs files from the chroot to a destination directory
@@ -239,7 +271,10 @@ impl Bootloader {
 
 		// Generate limine.cfg
 		let limine_cfg = root.join("boot/limine.cfg");
-		crate::tpl!("limine.cfg.tera" => { LIMINE_PREPEND_COMMENT, distro, vmlinuz, initramfs, cmd, volid } => &limine_cfg);
+		let (entries, default) = manifest.boot.live_entries(distro);
+		let timeout = manifest.boot.timeout();
+		let theme = manifest.theme.limine.install(&root.join("boot"))?;
+		crate::tpl!("limine.cfg.tera" => { LIMINE_PREPEND_COMMENT, entries, default, timeout, theme, vmlinuz, initramfs, cmd, volid } => &limine_cfg);
 
 		let binding = run_fun!(b2sum $limine_cfg)?;
 		let liminecfg_b2h = binding.split_whitespace().next().unwrap();
@@ -286,11 +321,16 @@ impl Bootloader {
 			cp -rv /usr/share/rEFInd/refind/icons/. $iso_tree/EFI/BOOT/icons/ 2>&1;
 		)?;
 
+		let theme = manifest.theme.refind.install(&iso_tree.join("EFI/BOOT"))?;
+
 		let (vmlinuz, initramfs) = self.cp_vmlinuz_initramfs(chroot, &iso_tree)?;
 		let volid = manifest.get_volid();
 
 		let refind_cfg = iso_tree.join("EFI/BOOT/refind.conf");
-		crate::tpl!("refind.cfg.tera" => { REFIND_PREPEND_COMMENT, distro, vmlinuz, initramfs, cmd, volid } => &refind_cfg);
+		let (entries, default) = manifest.boot.live_entries(distro);
+		let default_title = &entries[default].title;
+		let timeout = manifest.boot.timeout();
+		crate::tpl!("refind.cfg.tera" => { REFIND_PREPEND_COMMENT, entries, default_title, timeout, theme, vmlinuz, initramfs, cmd, volid } => &refind_cfg);
 
 		let mut nsh = std::fs::File::create(iso_tree.join("startup.nsh"))?;
 		// Point directly to the rEFInd EFI file
@@ -302,16 +342,370 @@ impl Bootloader {
 		Ok(())
 	}
 
+	/// Copies systemd-boot files to the ISO tree
+	///
+	/// systemd-boot can only load kernels from the ESP it was started from, so the
+	/// kernel, initramfs and Boot Loader Specification entries all end up in `efiboot.img`.
+	fn cp_systemd_boot(&self, manifest: &Manifest, chroot: &Path) -> Result<()> {
+		info!("Copying systemd-boot files");
+		let cmd = manifest.kernel_cmdline.as_deref().unwrap_or("");
+		let iso_tree = chroot.parent().unwrap().join(ISO_TREE);
+
+		self.cp_systemd_boot_efi(manifest, chroot, &iso_tree)?;
+
+		let volid = manifest.get_volid();
+		let options = format!("root=live:CDLABEL={volid} rd.live.image {cmd}");
+		let distro = manifest.distro.as_deref().unwrap_or("Linux");
+		let (entries, default) = manifest.boot.live_entries(distro);
+
+		if manifest.uki.is_some() {
+			// a single UKI in EFI/Linux instead of separate kernel and initramfs,
+			// so only the default entry can be booted
+			let options = format!("{} {}", options.trim(), entries[default].cmdline);
+			let uki = self.build_uki(manifest, chroot, &iso_tree, options.trim())?;
+			write_loader_conf(&iso_tree, &uki, manifest.boot.timeout())?;
+			return self.mkefiboot(chroot, manifest);
+		}
+
+		let (vmlinuz, initramfs) = self.cp_vmlinuz_initramfs(chroot, &iso_tree)?;
+		self.generate_bls_entries(
+			manifest,
+			&iso_tree,
+			&format!("/boot/{vmlinuz}"),
+			&format!("/boot/{initramfs}"),
+			&options,
+			&entries,
+		)?;
+
+		self.mkefiboot(chroot, manifest)?;
+
+		Ok(())
+	}
+
+	/// Sets up systemd-boot on the ESP of an installed disk
+	///
+	/// This is called after the root builder has populated `chroot`, while the disk
+	/// partitions are still mounted. `verity` are the kernel command line arguments
+	/// from [`PartitionLayout::format_verity`].
+	pub fn cp_systemd_boot_disk(
+		&self, manifest: &Manifest, chroot: &Path, disk: &PartitionLayout, verity: &[String],
+	) -> Result<()> {
+		info!("Setting up systemd-boot");
+		bail_let!(Some(esp) = disk.esp() => "systemd-boot requires an EFI system partition in the disk layout");
+		let esp = chroot.join(esp.mountpoint.trim_start_matches('/'));
+
+		self.cp_systemd_boot_efi(manifest, chroot, &esp)?;
+
+		let options = self.disk_cmdline(manifest, chroot, disk, verity)?;
+
+		if manifest.uki.is_some() {
+			// systemd-boot picks up UKIs in EFI/Linux by itself
+			let uki = self.build_uki(manifest, chroot, &esp, &options)?;
+			return write_loader_conf(&esp, &uki, manifest.boot.timeout());
+		}
+
+		let (vmlinuz, initramfs) = self.cp_vmlinuz_initramfs(chroot, &esp)?;
+
+		self.generate_bls_entries(
+			manifest,
+			&esp,
+			&format!("/boot/{vmlinuz}"),
+			&format!("/boot/{initramfs}"),
+			&options,
+			&disk_entries(manifest),
+		)
+	}
+
+	/// Sets up the Raspberry Pi firmware partition of an installed disk
+	///
+	/// The firmware, device trees and overlays come from the `bcm283x-firmware` and
+	/// `bcm283x-overlays` packages, which install them to `/boot/efi` in the chroot. The firmware
+	/// loads the kernel and initramfs copied next to them, with `config.txt` and `cmdline.txt`
+	/// generated from the manifest.
+	pub fn cp_raspberrypi_disk(
+		&self, manifest: &Manifest, chroot: &Path, disk: &PartitionLayout, verity: &[String],
+	) -> Result<()> {
+		info!("Setting up Raspberry Pi firmware");
+		bail_let!(Some(firmware) = disk.esp() => "Raspberry Pi firmware requires a partition with the `efi` filesystem in the disk layout");
+		let firmware = chroot.join(firmware.mountpoint.trim_start_matches('/'));
+
+		let packaged = chroot.join("boot/efi");
+		if packaged != firmware && packaged.exists() {
+			run_cmd!(cp -r $packaged/. $firmware 2>&1)?;
+		}
+		let has_firmware = fs::read_dir(&firmware)?
+			.any(|f| f.is_ok_and(|f| f.file_name().to_string_lossy().starts_with("start")));
+		if !has_firmware {
+			bail!("Cannot find the Raspberry Pi firmware in {firmware:?}, is bcm283x-firmware installed?");
+		}
+
+		let (vmlinuz, kernel_version) = self.find_vmlinuz(chroot)?;
+		bail_let!(Some(kernel_version) = kernel_version => "Cannot find a kernel in the chroot");
+		fs::copy(vmlinuz, firmware.join("vmlinuz"))?;
+		fs::copy(
+			chroot.join("boot").join(self.find_initramfs(chroot)?),
+			firmware.join("initramfs.img"),
+		)?;
+
+		// device trees matching the kernel instead of the ones shipped with the firmware
+		let dtbs = chroot.join("usr/lib/modules").join(&kernel_version).join("dtb/broadcom");
+		if dtbs.exists() {
+			for dtb in fs::read_dir(dtbs)? {
+				let dtb = dtb?.path();
+				if dtb.extension() == Some("dtb".as_ref()) {
+					fs::copy(&dtb, firmware.join(dtb.file_name().unwrap()))?;
+				}
+			}
+		}
+
+		let rpi = &manifest.raspberrypi;
+		let arm_64bit = manifest.dnf.arch.as_deref().unwrap_or(std::env::consts::ARCH) == "aarch64";
+		crate::tpl!("config.txt.tera" => {
+			RPI_PREPEND_COMMENT,
+			arm_64bit,
+			config: rpi.config,
+			dtoverlays: rpi.dtoverlays
+		} => firmware.join("config.txt"));
+
+		let cmdline = self.disk_cmdline(manifest, chroot, disk, verity)?;
+		let cmdline = format!("{cmdline} {}", rpi.cmdline.as_deref().unwrap_or(""));
+		// the firmware only reads the first line
+		crate::util::just_write(firmware.join("cmdline.txt"), format!("{}\n", cmdline.trim()))?;
+		Ok(())
+	}
+
+	/// Sets up U-Boot on an installed disk
+	///
+	/// Writes `/boot/extlinux/extlinux.conf`, which U-Boot's distro boot picks up, and copies
+	/// the device tree blobs of the kernel to `/boot` if the kernel package did not. The SPL and
+	/// U-Boot images are written later by [`UBootConfig::write_blobs`].
+	pub fn cp_uboot_disk(
+		&self, manifest: &Manifest, chroot: &Path, disk: &PartitionLayout, verity: &[String],
+	) -> Result<()> {
+		info!("Setting up U-Boot");
+		let (_, kernel_version) = self.find_vmlinuz(chroot)?;
+		bail_let!(Some(kernel_version) = kernel_version => "Cannot find a kernel in the chroot");
+		let modules = chroot.join("usr/lib/modules").join(&kernel_version);
+		let boot = chroot.join("boot");
+
+		let vmlinuz = format!("vmlinuz-{kernel_version}");
+		if !boot.join(&vmlinuz).exists() {
+			fs::copy(modules.join("vmlinuz"), boot.join(&vmlinuz))?;
+		}
+		let initramfs = self.find_initramfs(chroot)?;
+
+		let dtb = format!("dtb-{kernel_version}");
+		let dtb = if boot.join(&dtb).exists() {
+			Some(dtb)
+		} else if modules.join("dtb").exists() {
+			run_cmd!(cp -r $modules/dtb $boot/$dtb 2>&1)?;
+			Some(dtb)
+		} else {
+			warn!(kernel_version, "No device tree blobs for the kernel, U-Boot will use its own");
+			None
+		};
+		let fdt = manifest.uboot.as_ref().and_then(|u| u.fdt.as_deref());
+
+		// paths are relative to the partition extlinux.conf is on
+		let separate_boot = disk.mount_entries().iter().any(|e| e.mountpoint == "/boot");
+		let prefix = if separate_boot { "" } else { "/boot" };
+
+		let distro = manifest.distro.as_deref().unwrap_or("Linux");
+		let options = self.disk_cmdline(manifest, chroot, disk, verity)?;
+		let entries = disk_entries(manifest);
+		// in tenths of a second
+		let timeout = manifest.boot.timeout() * 10;
+		crate::tpl!("extlinux.conf.tera" => {
+			EXTLINUX_PREPEND_COMMENT,
+			distro,
+			entries,
+			timeout,
+			prefix,
+			vmlinuz,
+			initramfs,
+			dtb,
+			fdt,
+			options
+		} => boot.join("extlinux/extlinux.conf"));
+		Ok(())
+	}
+
+	/// Kernel command line for booting the system installed to `disk`
+	pub fn disk_cmdline(
+		&self, manifest: &Manifest, chroot: &Path, disk: &PartitionLayout, verity: &[String],
+	) -> Result<String> {
+		let cmd = manifest.kernel_cmdline.as_deref().unwrap_or("");
+		let root = if verity.iter().any(|a| a.starts_with("roothash=")) {
+			// set up by systemd-veritysetup in the initrd
+			"root=/dev/mapper/root ro".to_string()
+		} else {
+			let root_dev = run_fun!(findmnt -n --nofsroot -o SOURCE $chroot)?;
+			let root_uuid = run_fun!(blkid -s UUID -o value $root_dev)?;
+
+			// Root on a btrfs subvolume which isn't the default one
+			let rootflags = disk
+				.mount_entries()
+				.into_iter()
+				.find(|e| e.mountpoint == "/")
+				.and_then(|e| e.options.into_iter().find(|o| o.starts_with("subvol=")))
+				.map_or(String::new(), |o| format!(" rootflags={o}"));
+
+			format!("root=UUID={root_uuid}{rootflags} rw")
+		};
+
+		let extra =
+			disk.boot_cmdline()?.iter().chain(verity).map(|a| format!(" {a}")).collect::<String>();
+
+		Ok(format!("{root}{extra} {cmd}").trim().to_string())
+	}
+
+	/// Builds a Unified Kernel Image out of the kernel and initramfs in `chroot`, with `cmdline`
+	/// as the kernel command line. It ends up in `EFI/Linux/` under `esp`, the file name is returned
+	pub fn build_uki(
+		&self, manifest: &Manifest, chroot: &Path, esp: &Path, cmdline: &str,
+	) -> Result<String> {
+		bail_let!(Some(uki) = &manifest.uki => "Unified Kernel Images are not enabled in the manifest");
+		let (vmlinuz, kernel_version) = self.find_vmlinuz(chroot)?;
+		bail_let!(Some(kernel_version) = kernel_version => "Cannot find a kernel in the chroot");
+		let initramfs = chroot.join("boot").join(self.find_initramfs(chroot)?);
+
+		let stub = format!("linux{}.efi.stub", self.get_arch_short(manifest));
+		let stub = chroot.join("usr/lib/systemd/boot/efi").join(stub);
+		if !stub.exists() {
+			bail!("Cannot find {stub:?} in chroot, is the systemd EFI stub installed?");
+		}
+
+		let os_release = chroot.join("usr/lib/os-release");
+		let id = fs::read_to_string(&os_release)?
+			.lines()
+			.find_map(|l| l.strip_prefix("ID="))
+			.map_or("linux".to_string(), |id| id.trim_matches('"').to_string());
+		let name = format!("{id}-{kernel_version}.efi");
+
+		let out_dir = esp.join("EFI/Linux");
+		fs::create_dir_all(&out_dir)?;
+		let out = out_dir.join(&name);
+
+		let cmdline_file = chroot.parent().unwrap().join("uki-cmdline");
+		just_write(&cmdline_file, cmdline)?;
+
+		let tool = uki.tool.unwrap_or(if run_fun!(which ukify).is_ok() {
+			UkiTool::Ukify
+		} else {
+			UkiTool::Objcopy
+		});
+		info!(?out, ?tool, "Building Unified Kernel Image");
+
+		match tool {
+			UkiTool::Ukify => {
+				let mut args = vec![
+					format!("--linux={vmlinuz}"),
+					format!("--initrd={}", initramfs.display()),
+					format!("--cmdline=@{}", cmdline_file.display()),
+					format!("--os-release=@{}", os_release.display()),
+					format!("--stub={}", stub.display()),
+					format!("--uname={kernel_version}"),
+					format!("--output={}", out.display()),
+				];
+				if let Some(splash) = &uki.splash {
+					args.push(format!("--splash={}", splash.display()));
+				}
+				run_cmd!(ukify build $[args] 2>&1)?;
+			},
+			UkiTool::Objcopy => {
+				// the kernel goes last, so it can decompress itself in place
+				let mut sections = vec![("osrel", os_release), ("cmdline", cmdline_file.clone())];
+				if let Some(splash) = &uki.splash {
+					sections.push(("splash", splash.clone()));
+				}
+				sections.push(("initrd", initramfs));
+				sections.push(("linux", PathBuf::from(vmlinuz)));
+				objcopy_uki(&stub, &sections, &out)?;
+			},
+		}
+
+		fs::remove_file(cmdline_file)?;
+		Ok(name)
+	}
+
+	/// Copies the systemd-boot EFI binary from the chroot to `esp`, both as the
+	/// fallback boot path and under `EFI/systemd`
+	fn cp_systemd_boot_efi(&self, manifest: &Manifest, chroot: &Path, esp: &Path) -> Result<()> {
+		let arch_short = self.get_arch_short(manifest);
+		let bin = format!("systemd-boot{arch_short}.efi");
+		let src = chroot.join("usr/lib/systemd/boot/efi").join(&bin);
+		trace!(?src, "systemd-boot binary location");
+		if !src.exists() {
+			bail!("Cannot find {bin} in chroot, is systemd-boot installed?");
+		}
+
+		std::fs::create_dir_all(esp.join("EFI/BOOT"))?;
+		std::fs::create_dir_all(esp.join("EFI/systemd"))?;
+		std::fs::copy(&src, esp.join("EFI/systemd").join(&bin))?;
+		std::fs::copy(&src, esp.join(format!("EFI/BOOT/BOOT{}.EFI", arch_short.to_uppercase())))?;
+
+		Ok(())
+	}
+
+	/// Writes `loader/loader.conf` and one Boot Loader Specification entry per item of `entries` to `esp`
+	fn generate_bls_entries(
+		&self, manifest: &Manifest, esp: &Path, vmlinuz: &str, initramfs: &str, options: &str,
+		entries: &[BootEntry],
+	) -> Result<()> {
+		let loader = esp.join("loader");
+		std::fs::create_dir_all(loader.join("entries"))?;
+
+		for (i, entry) in entries.iter().enumerate() {
+			let options = format!("{options} {}", entry.cmdline);
+			crate::tpl!("systemd-boot.conf.tera" => {
+				SYSTEMD_BOOT_PREPEND_COMMENT,
+				title: entry.title,
+				vmlinuz,
+				initramfs,
+				options: options.trim()
+			} => loader.join(format!("entries/katsu-{i}.conf")));
+		}
+
+		let default = entries.iter().position(|e| e.default).unwrap_or(0);
+		write_loader_conf(esp, &format!("katsu-{default}.conf"), manifest.boot.timeout())
+	}
+
 	/// A clone of mkefiboot from lorax
 	/// Currently only works for PC, no mac support
-	fn mkefiboot(&self, chroot: &Path, _: &Manifest) -> Result<()> {
+	fn mkefiboot(&self, chroot: &Path, manifest: &Manifest) -> Result<()> {
 		let tree = chroot.parent().unwrap().join(ISO_TREE);
 
 		// TODO: Add mac boot support
 
+		if let Some(secureboot) = &manifest.secureboot {
+			if secureboot.enroll {
+				secureboot.write_enrollment(&tree)?;
+			}
+			secureboot.sign_tree(&tree.join("EFI"))?;
+			if tree.join("boot/vmlinuz").exists() {
+				secureboot.sign(&tree.join("boot/vmlinuz"))?;
+			}
+		}
+
+		// Files from the ISO tree to put on the ESP
+		let payload: &[&str] = match *self {
+			// systemd-boot can't read the ISO9660 tree, so it needs its kernels on the ESP
+			Self::SystemdBoot => &["EFI", "loader", "boot/vmlinuz", "boot/initramfs.img"],
+			_ => &["EFI/BOOT", "EFI/katsu"],
+		};
+
+		// the kernel and initramfs are missing when using a UKI
+		let payload = payload.iter().filter(|p| tree.join(p).exists()).collect::<Vec<_>>();
+
+		// Leave some headroom for FAT metadata, but never go below 25MiB
+		let size = payload
+			.iter()
+			.try_fold(0, |acc, p| Result::<_>::Ok(acc + crate::util::dir_size(&tree.join(p))?))?;
+		let size = (size + size / 10 + 4 * 1024 * 1024).max(25 * 1024 * 1024);
+
 		// make EFI disk
 		let sparse_path = &tree.join("boot/efiboot.img");
-		crate::util::create_sparse(sparse_path, 25 * 1024 * 1024)?; // 15MiB
+		crate::util::create_sparse(sparse_path, size)?;
 
 		// let's mount the disk as a loop device
 		let (ldp, hdl) = loopdev_with_file(sparse_path)?;
@@ -324,8 +718,8 @@ impl Bootloader {
 			mkdir -p /tmp/katsu.efiboot;
 			mount $ldp /tmp/katsu.efiboot;
 
-			mkdir -p /tmp/katsu.efiboot/EFI/BOOT;
-			cp -avr $tree/EFI/BOOT/. /tmp/katsu.efiboot/EFI/BOOT 2>&1;
+			cd $tree;
+			cp -avr --parents $[payload] /tmp/katsu.efiboot/ 2>&1;
 
 			umount /tmp/katsu.efiboot;
 		)?;
@@ -341,33 +735,18 @@ impl Bootloader {
 		// port from katsu 0.9.2 :3
 		std::fs::create_dir_all(&boot_imgs_dir)?; // create if not exist
 		if self.get_arch(manifest) == "x86_64" {
-			// Copy GRUB files for hybrid boot support
-			info!("Copying GRUB hybrid boot image");
-			let hybrid_img = chroot.join("usr/lib/grub/i386-pc/boot_hybrid.img");
-			trace!(?hybrid_img, "Source hybrid boot image location");
-			let dest = boot_imgs_dir.join("boot_hybrid.img");
-			trace!(?dest, "Destination hybrid boot image location");
-			if !hybrid_img.exists() {
-				warn!("Hybrid boot image not found at expected location");
-			}
-			std::fs::copy(&hybrid_img, &dest)?;
-			debug!("Successfully copied hybrid boot image");
+			self.cp_grub_hybrid_img(chroot, &boot_imgs_dir)?;
 		}
 
 		// Create necessary directories
 		self.create_grub_directories(&iso_tree, &boot_imgs_dir)?;
 
-		// Prepare configuration variables
-		let kernel_cmdline = manifest.kernel_cmdline.as_ref().map_or("", |s| s);
-		let volid = manifest.get_volid();
-		let distro = manifest.distro.as_ref().map_or("Linux", |s| s);
-
 		// Copy kernel and initramfs
 		let (vmlinuz, initramfs) =
 			self.copy_kernel_and_initramfs(chroot, &boot_imgs_dir, &iso_tree)?;
 
 		// Generate GRUB configuration
-		self.generate_grub_config(&iso_tree, volid, distro, &vmlinuz, &initramfs, kernel_cmdline)?;
+		self.generate_grub_config(manifest, &iso_tree, &vmlinuz, &initramfs)?;
 
 		// Set up EFI boot files
 		self.setup_efi_boot_files(manifest, &iso_tree)?;
@@ -381,6 +760,21 @@ impl Bootloader {
 		Ok(())
 	}
 
+	/// Copies GRUB's `boot_hybrid.img` MBR for hybrid (USB/CD) boot support
+	fn cp_grub_hybrid_img(&self, chroot: &Path, boot_imgs_dir: &Path) -> Result<()> {
+		info!("Copying GRUB hybrid boot image");
+		let hybrid_img = chroot.join("usr/lib/grub/i386-pc/boot_hybrid.img");
+		trace!(?hybrid_img, "Source hybrid boot image location");
+		let dest = boot_imgs_dir.join("boot_hybrid.img");
+		trace!(?dest, "Destination hybrid boot image location");
+		if !hybrid_img.exists() {
+			warn!("Hybrid boot image not found at expected location");
+		}
+		std::fs::copy(&hybrid_img, &dest)?;
+		debug!("Successfully copied hybrid boot image");
+		Ok(())
+	}
+
 	fn create_grub_directories(&self, iso_tree: &Path, boot_imgs_dir: &Path) -> Result<()> {
 		std::fs::create_dir_all(iso_tree)?;
 		std::fs::create_dir_all(boot_imgs_dir)?;
@@ -419,15 +813,24 @@ impl Bootloader {
 	}
 
 	fn generate_grub_config(
-		&self, iso_tree: &Path, volid: String, distro: &str, vmlinuz: &str, initramfs: &str,
-		kernel_cmdline: &str,
+		&self, manifest: &Manifest, iso_tree: &Path, vmlinuz: &str, initramfs: &str,
 	) -> Result<()> {
+		let kernel_cmdline = manifest.kernel_cmdline.as_ref().map_or("", |s| s);
+		let volid = manifest.get_volid();
+		let distro = manifest.distro.as_ref().map_or("Linux", |s| s);
+		let (entries, default) = manifest.boot.live_entries(distro);
+		let timeout = manifest.boot.timeout();
+		let theme = manifest.theme.grub.install(&iso_tree.join("boot/grub"))?;
+
 		// Generate grub.cfg using template
 		crate::tpl!(
 			"grub.cfg.tera" => {
 				GRUB_PREPEND_COMMENT,
 				volid,
-				distro,
+				entries,
+				default,
+				timeout,
+				theme,
 				vmlinuz: vmlinuz.to_string(),
 				initramfs: initramfs.to_string(),
 				cmd: kernel_cmdline.to_string()
@@ -504,13 +907,10 @@ impl Bootloader {
 		};
 
 		debug!("Generating Grub images");
-		cmd_lib::run_cmd!(
-			// Create eltorito.img for ISO boot
-			grub2-mkimage -O $arch_out -d $chroot/usr/lib/grub/$arch -o $iso_tree/boot/eltorito.img -p /boot/grub iso9660 $[arch_modules] 2>&1;
+		self.grub_mkimage_eltorito(chroot, iso_tree, arch, arch_out, &arch_modules)?;
 
-			// Create rescue image for EFI files
-			grub2-mkrescue -o $iso_tree/../efiboot.img;
-		)?;
+		// Create rescue image for EFI files
+		cmd_lib::run_cmd!(grub2-mkrescue -o $iso_tree/../efiboot.img)?;
 
 		debug!("Copying EFI files from Grub rescue image");
 		let (loop_device, handle) = loopdev_with_file(&iso_tree.join("../efiboot.img"))?;
@@ -527,6 +927,16 @@ impl Bootloader {
 		Ok(())
 	}
 
+	/// Creates `boot/eltorito.img` in the ISO tree for El Torito boot
+	fn grub_mkimage_eltorito(
+		&self, chroot: &Path, iso_tree: &Path, arch: &str, arch_out: &str, modules: &[&str],
+	) -> Result<()> {
+		cmd_lib::run_cmd!(
+			grub2-mkimage -O $arch_out -d $chroot/usr/lib/grub/$arch -o $iso_tree/boot/eltorito.img -p /boot/grub iso9660 $[modules] 2>&1;
+		)?;
+		Ok(())
+	}
+
 	/// Copies the bootloader files to the live OS image
 	///
 	/// This method copies all necessary bootloader files to the ISO tree to create
@@ -543,12 +953,17 @@ impl Bootloader {
 	/// * `Result<()>` - Success or failure with error details
 	pub fn copy_liveos(&self, manifest: &Manifest, chroot: &Path) -> Result<()> {
 		info!("Copying bootloader files");
+		if manifest.uki.is_some() && *self != Self::SystemdBoot {
+			warn!("Unified Kernel Images are only used on ISOs booted with systemd-boot, skipping");
+		}
 		match *self {
-			Self::Grub => self.cp_grub(manifest, chroot)?,
+			Self::Grub | Self::GrubHybrid => self.cp_grub(manifest, chroot)?,
 			Self::Limine => self.cp_limine(manifest, chroot)?,
-			Self::SystemdBoot => todo!(),
-			Self::GrubBios => self.cp_grub_bios(chroot)?,
+			Self::SystemdBoot => self.cp_systemd_boot(manifest, chroot)?,
+			Self::GrubBios => self.cp_grub_bios(manifest, chroot)?,
 			Self::REFInd => self.cp_refind(manifest, chroot)?,
+			Self::UBoot => bail!("U-Boot is only supported for disk images"),
+			Self::RaspberryPi => bail!("Raspberry Pi firmware is only supported for disk images"),
 		}
 		Ok(())
 	}
@@ -557,21 +972,50 @@ impl Bootloader {
 	///
 	/// This method is responsible for setting up the legacy BIOS boot environment
 	/// using GRUB. It's used when the bootloader type is GrubBios.
+	/// The resulting ISO only boots through El Torito, there is no EFI image.
 	///
 	/// # Arguments
 	///
-	/// * `_chroot` - The path to the chroot directory
+	/// * `manifest` - The manifest containing configuration information
+	/// * `chroot` - The path to the chroot directory
 	///
 	/// # Returns
 	///
 	/// * `Result<()>` - Success or failure with error details
-	pub fn cp_grub_bios(&self, _chroot: &Path) -> Result<()> {
-		todo!()
+	pub fn cp_grub_bios(&self, manifest: &Manifest, chroot: &Path) -> Result<()> {
+		let arch = self.get_arch(manifest);
+		if arch != "x86_64" {
+			bail!("GRUB BIOS is only supported on x86_64, not {arch}");
+		}
+
+		let iso_tree = chroot.parent().unwrap().join(ISO_TREE);
+		let boot_imgs_dir = chroot.parent().unwrap().join(BOOTIMGS);
+		self.create_grub_directories(&iso_tree, &boot_imgs_dir)?;
+		self.cp_grub_hybrid_img(chroot, &boot_imgs_dir)?;
+
+		let (vmlinuz, initramfs) =
+			self.copy_kernel_and_initramfs(chroot, &boot_imgs_dir, &iso_tree)?;
+
+		self.generate_grub_config(manifest, &iso_tree, &vmlinuz, &initramfs)?;
+
+		// GRUB loads the modules `grub.cfg` asks for from the ISO at runtime
+		let modules = iso_tree.join("boot/grub/i386-pc");
+		std::fs::create_dir_all(&modules)?;
+		cmd_lib::run_cmd!(cp -r $chroot/usr/lib/grub/i386-pc/. $modules 2>&1)?;
+
+		debug!("Generating Grub images");
+		self.grub_mkimage_eltorito(chroot, &iso_tree, "i386-pc", "i386-pc-eltorito", &["biosdisk"])
 	}
 }
 
 pub trait RootBuilder {
 	fn build(&self, chroot: &Path, manifest: &Manifest) -> Result<()>;
+
+	/// Finishes a root filesystem built without the disk layout and post-install scripts,
+	/// once it has been copied to the partitions mounted to `chroot`
+	fn finish_staged(&self, _chroot: &Path, _manifest: &Manifest) -> Result<()> {
+		Ok(())
+	}
 }
 
 fn _default_dnf() -> String {
@@ -700,6 +1144,63 @@ pub struct DnfRootBuilder {
 	pub global_options: Vec<String>,
 }
 
+/// Writes the mount units of the disk layout to the chroot and enables them
+fn write_mount_units(disk: &PartitionLayout, chroot: &Path) -> Result<()> {
+	let dir = chroot.join("etc/systemd/system");
+	for (name, unit) in disk.mount_units(chroot)? {
+		just_write(dir.join(&name), unit)?;
+		let wants = dir.join(if name.ends_with(".swap") {
+			"swap.target.wants"
+		} else {
+			"local-fs.target.wants"
+		});
+		fs::create_dir_all(&wants)?;
+		std::os::unix::fs::symlink(format!("../{name}"), wants.join(&name))?;
+	}
+	Ok(())
+}
+
+/// Writes the fstab or mount units and the crypttab for the partitions mounted to `chroot`
+fn write_disk_config(disk: &PartitionLayout, chroot: &Path) -> Result<()> {
+	if disk.mount_units {
+		write_mount_units(disk, chroot)?;
+	} else {
+		// write fstab to chroot
+		crate::util::just_write(chroot.join("etc/fstab"), disk.fstab(chroot)?)?;
+	}
+	if let Some(crypttab) = disk.crypttab()? {
+		crate::util::just_write(chroot.join("etc/crypttab"), crypttab)?;
+	}
+	Ok(())
+}
+
+/// Generates `/boot/grub2/grub.cfg` in `chroot`
+fn grub_mkconfig(chroot: &Path) {
+	info!("Attempting to run grub2-mkconfig");
+	// crate::chroot_run_cmd!(&chroot,
+	// 	echo "GRUB_DISABLE_OS_PROBER=true" > /etc/default/grub;
+	// )?;
+
+	// While grub2-mkconfig may not return 0 it should still work
+	// todo: figure out why it still wouldn't write the file to /boot/grub2/grub.cfg
+	//       but works when run inside a post script
+	let res = crate::util::enter_chroot_run(chroot, || {
+		std::process::Command::new("grub2-mkconfig")
+			.arg("-o")
+			.arg("/boot/grub2/grub.cfg")
+			.status()?;
+		Ok(())
+	});
+
+	if let Err(e) = res {
+		warn!(?e, "grub2-mkconfig not returning 0, continuing anyway");
+	}
+
+	// crate::chroot_run_cmd!(&chroot,
+	// 	rm -f /etc/default/grub;
+	// )?;
+}
+
 impl RootBuilder for DnfRootBuilder {
 	fn build(&self, chroot: &Path, manifest: &Manifest) -> Result<()> {
 		info!("Running Pre-install scripts");
@@ -708,8 +1209,7 @@ impl RootBuilder for DnfRootBuilder {
 
 		// todo: generate different kind of fstab for iso and other builds
 		if let Some(disk) = &manifest.disk {
-			// write fstab to chroot
-			crate::util::just_write(chroot.join("etc/fstab"), disk.fstab(chroot)?)?;
+			write_disk_config(disk, chroot)?;
 		}
 
 		let mut packages = self.packages.clone();
@@ -762,30 +1262,25 @@ impl RootBuilder for DnfRootBuilder {
 			manifest.users.iter().try_for_each(|user| user.add_to_chroot(&chroot))?;
 		}
 
-		if manifest.bootloader == Bootloader::GrubBios || manifest.bootloader == Bootloader::Grub {
-			info!("Attempting to run grub2-mkconfig");
-			// crate::chroot_run_cmd!(&chroot,
-			// 	echo "GRUB_DISABLE_OS_PROBER=true" > /etc/default/grub;
-			// )?;
-
-			// While grub2-mkconfig may not return 0 it should still work
-			// todo: figure out why it still wouldn't write the file to /boot/grub2/grub.cfg
-			//       but works when run inside a post script
-			let res = crate::util::enter_chroot_run(&chroot, || {
-				std::process::Command::new("grub2-mkconfig")
-					.arg("-o")
-					.arg("/boot/grub2/grub.cfg")
-					.status()?;
+		if let Some(theme) = &manifest.theme.plymouth {
+			info!(theme, "Setting Plymouth theme");
+			// rebuild the initramfs for installed systems, live images get theirs from dracut later
+			// and roots staged for `size: auto` disks from `finish_staged`
+			let rebuild = if manifest.disk.is_some() { vec!["-R"] } else { vec![] };
+			crate::util::enter_chroot_run(&chroot, || {
+				cmd_lib::run_cmd!(plymouth-set-default-theme $theme $[rebuild] 2>&1)?;
 				Ok(())
-			});
+			})?;
+		}
 
-			if let Err(e) = res {
-				warn!(?e, "grub2-mkconfig not returning 0, continuing anyway");
+		if matches!(
+			manifest.bootloader,
+			Bootloader::Grub | Bootloader::GrubBios | Bootloader::GrubHybrid
+		) {
+			if !manifest.theme.grub.is_empty() {
+				write_grub_theme_defaults(&manifest.theme.grub, &chroot)?;
 			}
-
-			// crate::chroot_run_cmd!(&chroot,
-			// 	rm -f /etc/default/grub;
-			// )?;
+			grub_mkconfig(&chroot);
 		}
 
 		// now, let's run some funny post-install scripts
@@ -794,6 +1289,57 @@ impl RootBuilder for DnfRootBuilder {
 
 		run_all_scripts(&manifest.scripts.post, &chroot, true)
 	}
+
+	fn finish_staged(&self, chroot: &Path, manifest: &Manifest) -> Result<()> {
+		if let Some(disk) = &manifest.disk {
+			write_disk_config(disk, chroot)?;
+		}
+
+		// dracut only picks up the crypttab, LVM and mounts now
+		info!("Regenerating initramfs and boot entries");
+		for entry in fs::read_dir(chroot.join("usr/lib/modules"))? {
+			let kver = entry?.file_name().to_string_lossy().to_string();
+			let kernel = format!("/usr/lib/modules/{kver}/vmlinuz");
+			if !chroot.join(kernel.trim_start_matches('/')).exists() {
+				continue;
+			}
+			crate::util::enter_chroot_run(chroot, || {
+				cmd_lib::run_cmd!(kernel-install add $kver $kernel 2>&1)?;
+				Ok(())
+			})?;
+		}
+
+		if matches!(
+			manifest.bootloader,
+			Bootloader::Grub | Bootloader::GrubBios | Bootloader::GrubHybrid
+		) {
+			grub_mkconfig(chroot);
+		}
+
+		info!("Running post-install scripts");
+
+		run_all_scripts(&manifest.scripts.post, chroot, true)
+	}
+}
+
+/// Installs a GRUB theme to `/boot/grub2` of `chroot` and points `/etc/default/grub` at it
+fn write_grub_theme_defaults(theme: &GrubTheme, chroot: &Path) -> Result<()> {
+	let theme = theme.install(&chroot.join("boot/grub2"))?;
+	let mut defaults = std::fs::OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(chroot.join("etc/default/grub"))?;
+	if let Some(theme) = &theme.theme {
+		writeln!(defaults, "GRUB_THEME=\"/boot/grub2/{}\"", theme.display())?;
+	}
+	if let Some(background) = &theme.background {
+		writeln!(defaults, "GRUB_BACKGROUND=\"/boot/grub2/{}\"", background.display())?;
+	}
+	if let Some(font) = &theme.font {
+		writeln!(defaults, "GRUB_FONT=\"/boot/grub2/{}\"", font.display())?;
+	}
+	writeln!(defaults, "GRUB_TERMINAL_OUTPUT=\"gfxterm\"")?;
+	Ok(())
 }
 
 #[tracing::instrument(skip(chroot, is_post))]
@@ -895,79 +1441,510 @@ pub trait ImageBuilder {
 		&self, chroot: &Path, image: &Path, manifest: &Manifest, skip_phases: &SkipPhases,
 	) -> Result<()>;
 }
+/// Partition table to create for `disk` when booting with `bootloader`
+fn disk_label(disk: &PartitionLayout, bootloader: &Bootloader) -> DiskLabel {
+	// the Raspberry Pi boot ROM only reads MBR partition tables
+	disk.label.unwrap_or(if *bootloader == Bootloader::RaspberryPi {
+		DiskLabel::Msdos
+	} else {
+		DiskLabel::Gpt
+	})
+}
+
+/// Copies a root filesystem built in `staged` to the partitions mounted to `chroot`
+///
+/// FAT filesystems cannot hold ownership, permissions or xattrs, so their contents are copied
+/// separately without them.
+fn copy_staged_root(disk: &PartitionLayout, staged: &Path, chroot: &Path) -> Result<()> {
+	info!(?staged, "Copying staged root filesystem to disk");
+	let fat: Vec<_> = disk
+		.mount_entries()
+		.into_iter()
+		.filter(|e| matches!(e.filesystem, "efi" | "vfat" | "fat"))
+		.map(|e| e.mountpoint.trim_start_matches('/').to_string())
+		.collect();
+	let excludes: Vec<_> = fat.iter().map(|mp| format!("--exclude=./{mp}")).collect();
+	cmd_lib::run_cmd!(
+		tar -C $staged --xattrs --acls --selinux $[excludes] -cf - . |
+			tar -C $chroot --xattrs --xattrs-include=* --acls --selinux -xpf -
+	)?;
+	for mp in &fat {
+		let src = staged.join(mp).join(".");
+		let dest = chroot.join(mp);
+		if src.exists() {
+			cmd_lib::run_cmd!(cp -r $src $dest)?;
+		}
+	}
+	Ok(())
+}
+
+/// Partitions `ldp` with the manifest's disk layout, mounts it to `chroot`, then builds
+/// the root filesystem and sets up the bootloader on it
+///
+/// Shared between [`DiskImageBuilder`] and [`DeviceInstaller`], the only difference being
+/// whether `ldp` is a loop device backed by an image file or a real block device.
+/// If `staged` is set, the root filesystem was already built there and is copied instead.
+///
+/// Returns the dm-verity kernel command line arguments, see [`PartitionLayout::format_verity`].
+fn install_to_disk(
+	ldp: &PathBuf, chroot: &Path, manifest: &Manifest, bootloader: &Bootloader,
+	root_builder: &dyn RootBuilder, staged: Option<&Path>, wipe: WipePolicy,
+) -> Result<Vec<String>> {
+	bail_let!(Some(disk) = &manifest.disk => "Disk layout not specified");
+	let bios = matches!(bootloader, Bootloader::GrubBios | Bootloader::GrubHybrid);
+	let arch = manifest.dnf.arch.as_deref().unwrap_or(std::env::consts::ARCH);
+
+	let verity_root = disk.partitions.iter().any(|p| p.verity && p.mountpoint == "/");
+	// systemd-boot and the Raspberry Pi firmware boot from the ESP, the others from /boot
+	let boot_on_esp = matches!(bootloader, Bootloader::SystemdBoot | Bootloader::RaspberryPi);
+	if verity_root && !boot_on_esp && !disk.mount_entries().iter().any(|e| e.mountpoint == "/boot")
+	{
+		bail!("A dm-verity protected root needs a separate /boot partition for the boot entries");
+	}
+
+	let label = disk_label(disk, bootloader);
+	if *bootloader == Bootloader::RaspberryPi && label == DiskLabel::Gpt {
+		bail!("The Raspberry Pi firmware needs an `msdos` or `hybrid` disk label");
+	}
+	// GRUB embeds its core image there on GPT, right after the MBR otherwise
+	if bios
+		&& label != DiskLabel::Msdos
+		&& !disk.partitions.iter().any(|p| p.partition_type == PartitionType::BiosGrub)
+	{
+		bail!("GRUB for BIOS on a GPT disk needs a `bios-grub` partition");
+	}
+	if *bootloader == Bootloader::GrubHybrid && disk.esp().is_none() {
+		bail!("Booting with both BIOS and UEFI needs an EFI system partition in the disk layout");
+	}
+
+	// Partition disk
+	disk.apply(ldp, arch, label, wipe)?;
+
+	// Mount partitions to chroot
+	disk.mount_to_chroot(ldp, chroot)?;
+
+	if let Some(staged) = staged {
+		copy_staged_root(disk, staged, chroot)?;
+		root_builder.finish_staged(&chroot.canonicalize()?, manifest)?;
+	} else {
+		root_builder.build(&chroot.canonicalize()?, manifest)?;
+	}
+
+	if let Some(secureboot) = &manifest.secureboot {
+		for kernel in SecureBootConfig::kernels(chroot)? {
+			secureboot.sign(&kernel)?;
+		}
+	}
+
+	// the protected filesystems are read-only from here on
+	let verity = disk.format_verity(ldp, chroot)?;
+
+	if *bootloader == Bootloader::SystemdBoot {
+		bootloader.cp_systemd_boot_disk(manifest, chroot, disk, &verity)?;
+	} else if *bootloader == Bootloader::UBoot {
+		bootloader.cp_uboot_disk(manifest, chroot, disk, &verity)?;
+	} else if *bootloader == Bootloader::RaspberryPi {
+		bootloader.cp_raspberrypi_disk(manifest, chroot, disk, &verity)?;
+	} else {
+		// GRUB boots the entries written by kernel-install, which know nothing of the disk layout
+		let args =
+			disk.boot_cmdline()?.into_iter().chain(verity.iter().cloned()).collect::<Vec<_>>();
+		set_bls_cmdline(&chroot.join("boot/loader/entries"), &args)?;
+		if manifest.uki.is_some() {
+			bail_let!(Some(esp) = disk.esp() => "Unified Kernel Images need an EFI system partition in the disk layout");
+			let esp = chroot.join(esp.mountpoint.trim_start_matches('/'));
+			let cmdline = bootloader.disk_cmdline(manifest, chroot, disk, &verity)?;
+			bootloader.build_uki(manifest, chroot, &esp, &cmdline)?;
+		}
+	}
+
+	if let Some(secureboot) = &manifest.secureboot {
+		bail_let!(Some(esp) = disk.esp() => "Secure Boot signing needs an EFI system partition in the disk layout");
+		let esp = chroot.join(esp.mountpoint.trim_start_matches('/'));
+		if secureboot.enroll {
+			secureboot.write_enrollment(&esp)?;
+		}
+		secureboot.sign_tree(&esp)?;
+		secureboot.verify_tree(&esp)?;
+		// kernels under a verity protected /usr have not changed since they were signed
+		SecureBootConfig::kernels(chroot)?.iter().try_for_each(|k| secureboot.verify(k))?;
+	}
+
+	if let Some(uboot) = manifest.uboot.as_ref().filter(|_| *bootloader == Bootloader::UBoot) {
+		uboot.write_blobs(ldp, disk, label)?;
+	}
+
+	if bios {
+		info!("Setting up BIOS boot");
+
+		// Let's use grub2-install to bless the disk, the UEFI side comes from the packages
+		// in the ESP and shares /boot/grub2/grub.cfg with it
+
+		info!("Blessing disk image with MBR");
+		let boot_dir = chroot.join("boot");
+		run_cmd!(grub2-install --target=i386-pc --boot-directory=$boot_dir $ldp 2>&1)
+			.map_err(|e| color_eyre::eyre::eyre!("grub2-install failed: {e}"))?;
+	}
+
+	disk.unmount_from_chroot(chroot)?;
+	Ok(verity)
+}
+
+/// Writes `loader/loader.conf` for systemd-boot to `esp`, booting the entry `default` by default
+fn write_loader_conf(esp: &Path, default: &str, timeout: u32) -> Result<()> {
+	crate::tpl!("loader.conf.tera" => { SYSTEMD_BOOT_PREPEND_COMMENT, default, timeout } => esp.join("loader/loader.conf"));
+	Ok(())
+}
+
+/// Virtual addresses for UKI sections of `sizes` bytes, placed one after another behind the
+/// sections of the stub ending at `stub_end`, each aligned to `align`
+fn uki_section_vmas(stub_end: u64, align: u64, sizes: &[u64]) -> Vec<u64> {
+	let mut next = stub_end.next_multiple_of(align);
+	sizes
+		.iter()
+		.map(|size| {
+			let vma = next;
+			next = (vma + size).next_multiple_of(align);
+			vma
+		})
+		.collect()
+}
+
+#[test]
+fn test_uki_section_vmas() {
+	assert_eq!(
+		uki_section_vmas(0x1_4b8f_1234, 0x1000, &[0x120, 0x1000, 0x2345]),
+		[0x1_4b8f_2000, 0x1_4b8f_3000, 0x1_4b8f_4000]
+	);
+	assert_eq!(uki_section_vmas(0x2000, 0x1000, &[1]), [0x2000]);
+}
+
+/// Assembles a Unified Kernel Image by adding `sections` to the systemd EFI `stub` with objcopy
+fn objcopy_uki(stub: &Path, sections: &[(&str, PathBuf)], out: &Path) -> Result<()> {
+	// Idx Name Size VMA LMA File-off Algn Flags
+	let headers = run_fun!(objdump -h -w $stub)?;
+	let stub_end = headers
+		.lines()
+		.map(|l| l.split_whitespace().collect::<Vec<_>>())
+		.filter(|cols| cols.len() > 3 && cols[0].parse::<u32>().is_ok())
+		.map(|cols| Ok(u64::from_str_radix(cols[2], 16)? + u64::from_str_radix(cols[3], 16)?))
+		.collect::<Result<Vec<_>>>()?
+		.into_iter()
+		.max();
+	bail_let!(Some(stub_end) = stub_end => "Cannot read the sections of {stub:?}");
+
+	let private = run_fun!(objdump -p $stub)?;
+	bail_let!(
+		Some(align) = private.lines().find_map(|l| l.strip_prefix("SectionAlignment"))
+			=> "Cannot read the section alignment of {stub:?}"
+	);
+	let align = u64::from_str_radix(align.trim(), 16)?;
+
+	let sizes =
+		sections.iter().map(|(_, p)| Ok(fs::metadata(p)?.len())).collect::<Result<Vec<_>>>()?;
+	let mut args = vec![];
+	for ((name, path), vma) in sections.iter().zip(uki_section_vmas(stub_end, align, &sizes)) {
+		args.push(format!("--add-section=.{name}={}", path.display()));
+		args.push(format!("--change-section-vma=.{name}={vma:#x}"));
+	}
+
+	trace!(?args, "objcopy");
+	run_cmd!(objcopy $[args] $stub $out 2>&1)?;
+	Ok(())
+}
+
+/// Adds kernel command line arguments `args` to the Boot Loader Specification entries in `entries`,
+/// i.e. the ones to unlock and activate the disk layout and the dm-verity ones
+fn set_bls_cmdline(entries: &Path, args: &[String]) -> Result<()> {
+	if args.is_empty() {
+		return Ok(());
+	}
+	if !entries.exists() {
+		warn!(?entries, "No boot entries to add the kernel command line arguments to");
+		return Ok(());
+	}
+
+	for entry in fs::read_dir(entries)? {
+		let path = entry?.path();
+		if path.extension() != Some("conf".as_ref()) {
+			continue;
+		}
+		debug!(?path, ?args, "Adding kernel command line arguments to boot entry");
+		just_write(&path, bls_entry_with_args(&fs::read_to_string(&path)?, args))?;
+	}
+	Ok(())
+}
+
+/// `conf` with `args` added to its `options` line, skipping the ones already there
+fn bls_entry_with_args(conf: &str, args: &[String]) -> String {
+	let root = args.iter().any(|a| a.starts_with("roothash="));
+	let conf = conf.lines().map(|line| {
+		let Some(options) = line.strip_prefix("options ") else { return line.to_string() };
+		let mut options = if root {
+			// set up by systemd-veritysetup in the initrd
+			let kept = options.split_whitespace().filter(|o| {
+				!(o.starts_with("root=") || o.starts_with("rootflags=") || *o == "rw" || *o == "ro")
+			});
+			["root=/dev/mapper/root", "ro"].into_iter().chain(kept).collect::<Vec<_>>()
+		} else {
+			options.split_whitespace().collect()
+		};
+		for arg in args {
+			if !options.contains(&arg.as_str()) {
+				options.push(arg);
+			}
+		}
+		format!("options {}", options.join(" "))
+	});
+	conf.collect::<Vec<_>>().join("\n") + "\n"
+}
+
+#[test]
+fn test_bls_entry_with_args() {
+	// as written by kernel-install for GRUB
+	let conf =
+		"title Ultramarine Linux (6.8.5-301.fc40.x86_64) 40\nversion 6.8.5-301.fc40.x86_64\n\
+		linux /vmlinuz-6.8.5-301.fc40.x86_64\noptions root=UUID=abcd ro rhgb quiet\n\
+		grub_users $grub_users\n";
+	let args = ["rd.luks.uuid=1234".to_string(), "rd.lvm.vg=katsu".to_string()];
+	let patched = bls_entry_with_args(conf, &args);
+	assert!(patched
+		.contains("\noptions root=UUID=abcd ro rhgb quiet rd.luks.uuid=1234 rd.lvm.vg=katsu\n"));
+	assert!(patched.contains("\ngrub_users $grub_users\n"));
+	assert_eq!(bls_entry_with_args(&patched, &args), patched);
+
+	let verity = ["roothash=ff".to_string()];
+	let patched = bls_entry_with_args(conf, &verity);
+	assert!(patched.contains("\noptions root=/dev/mapper/root ro rhgb quiet roothash=ff\n"));
+}
+
+/// Total apparent size of `paths` in bytes, counting files under several of them once
+fn du_bytes(paths: &[PathBuf]) -> Result<u64> {
+	let out = cmd_lib::run_fun!(du -sbc $[paths])?;
+	bail_let!(Some(total) = out.lines().last().and_then(|l| l.split_whitespace().next()) => "Cannot read the output of du");
+	Ok(total.parse()?)
+}
+
 /// Creates a disk image, then installs to it
-#[allow(dead_code)]
 pub struct DiskImageBuilder {
 	pub image: PathBuf,
 	pub bootloader: Bootloader,
 	pub root_builder: Box<dyn RootBuilder>,
+	pub wipe: WipePolicy,
 }
 
 impl ImageBuilder for DiskImageBuilder {
 	fn build(
 		&self, chroot: &Path, image: &Path, manifest: &Manifest, _: &SkipPhases,
 	) -> Result<()> {
+		use std::os::unix::fs::FileTypeExt;
+
 		// create sparse file on disk
 		bail_let!(Some(disk) = &manifest.disk => "Disk layout not specified");
-		bail_let!(Some(disk_size) = &disk.size => "Disk size not specified");
-		let sparse_path = &image.canonicalize()?.join("katsu.img");
-		crate::util::create_sparse(sparse_path, disk_size.as_u64())?;
-
-		// if let Some(disk) = manifest.disk.as_ref() {
-		// 	disk.apply(&loopdev.path().unwrap())?;
-		// 	disk.mount_to_chroot(&loopdev.path().unwrap(), &chroot)?;
-		// 	disk.unmount_from_chroot(&loopdev.path().unwrap(), &chroot)?;
-		// }
-		let uefi = { self.bootloader != Bootloader::GrubBios };
-		let arch = manifest.dnf.arch.as_deref().unwrap_or(std::env::consts::ARCH);
+		bail_let!(Some(size) = &disk.size => "Disk size not specified");
+		if disk.bmap && disk.format != DiskFormat::Raw {
+			bail!("bmap files can only be generated for raw disk images, not {:?}", disk.format);
+		}
+		// Non-raw images are converted from a scratch raw image in the workdir
+		let sparse_path = &if disk.format == DiskFormat::Raw {
+			self.image.clone()
+		} else {
+			image.join("katsu.raw")
+		};
+		if fs::metadata(&self.image).is_ok_and(|m| m.file_type().is_block_device()) {
+			bail!(
+				"{:?} is a block device, use the `device` output format with --target to install to it",
+				self.image
+			);
+		}
+		if let Some(parent) = sparse_path.parent() {
+			fs::create_dir_all(parent)?;
+		}
+
+		let staging = chroot.with_file_name("staging");
+		let (disk_size, staged) = match size {
+			DiskSize::Fixed(size) => (size.as_u64(), None),
+			DiskSize::Auto => (self.stage_root(&staging, manifest, disk)?, Some(staging.as_path())),
+		};
+
+		info!(?sparse_path, disk_size, "Creating disk image");
+		crate::util::create_sparse(sparse_path, disk_size)?;
 
 		let (ldp, hdl) = loopdev_with_file(sparse_path)?;
 
-		// Partition disk
-		disk.apply(&ldp, arch)?;
+		let verity = install_to_disk(
+			&ldp,
+			chroot,
+			manifest,
+			&self.bootloader,
+			self.root_builder.as_ref(),
+			staged,
+			self.wipe,
+		)?;
+
+		drop(hdl);
+		self.write_verity_hashes(&verity)?;
+
+		if staged.is_some() {
+			fs::remove_dir_all(&staging)?;
+		}
+
+		if disk.format != DiskFormat::Raw {
+			self.convert(sparse_path, disk)?;
+			fs::remove_file(sparse_path)?;
+		}
 
-		// Mount partitions to chroot
-		disk.mount_to_chroot(&ldp, chroot)?;
+		if disk.bmap {
+			self.bmap()?;
+		}
+
+		let image = match disk.compression {
+			Some(compression) => self.compress(compression)?,
+			None => self.image.clone(),
+		};
 
-		self.root_builder.build(&chroot.canonicalize()?, manifest)?;
+		info!(?image, "Disk image written");
+		Ok(())
+	}
+}
 
-		if !uefi {
-			info!("Not UEFI, Setting up extra configs");
+impl DiskImageBuilder {
+	/// Builds the root filesystem in `staging` to measure it for `size: auto`, returning the
+	/// disk size needed to hold it
+	fn stage_root(
+		&self, staging: &Path, manifest: &Manifest, disk: &PartitionLayout,
+	) -> Result<u64> {
+		info!(?staging, "Building root filesystem to measure it");
+		fs::create_dir_all(staging)?;
+		// the disk specific parts are done by `RootBuilder::finish_staged` once it is copied
+		let mut staged_manifest = Manifest { disk: None, ..manifest.clone() };
+		staged_manifest.scripts.post.clear();
+		self.root_builder.build(&staging.canonicalize()?, &staged_manifest)?;
+
+		// whatever goes to the partitions and volumes with a fixed size does not need space on
+		// the others, every file belongs to the most nested mountpoint above it
+		let entries = disk.mount_entries();
+		let fixed = |e: &MountEntry| match e.source {
+			MountSource::Partition(i) => {
+				disk.partitions[i - 1].size.is_some_and(|s| s.fixed().is_some())
+			},
+			MountSource::LogicalVolume { vg, lv } => (disk.lvm.iter())
+				.filter(|g| g.name == vg)
+				.flat_map(|g| &g.volumes)
+				.any(|v| v.name == lv && v.size.is_some()),
+		};
+		let dir = |mp: &str| staging.join(mp.trim_start_matches('/'));
+		let mut content = 0;
+		for entry in entries.iter().filter(|e| !fixed(e) && dir(e.mountpoint).exists()) {
+			let nested: Vec<_> = (entries.iter())
+				.filter(|n| n.mountpoint != entry.mountpoint)
+				.filter(|n| Path::new(n.mountpoint).starts_with(entry.mountpoint))
+				.map(|n| dir(n.mountpoint))
+				.filter(|p| p.exists())
+				.collect();
+			let all = [vec![dir(entry.mountpoint)], nested.clone()].concat();
+			let total = du_bytes(&all)?;
+			content += total.saturating_sub(if nested.is_empty() { 0 } else { du_bytes(&nested)? });
+		}
 
-			// Let's use grub2-install to bless the disk
+		let size = disk.auto_size(disk_label(disk, &self.bootloader), content)?;
+		info!(content, size, "Measured root filesystem");
+		Ok(size)
+	}
 
-			info!("Blessing disk image with MBR");
-			std::process::Command::new("grub2-install")
-				.arg("--target=i386-pc")
-				.arg(format!("--boot-directory={}", chroot.join("boot").display()))
-				.arg(ldp)
-				.output()
-				.map_err(|e| color_eyre::eyre::eyre!("Failed to execute grub2-install: {}", e))?;
+	/// Converts the raw image at `raw` to the disk layout's format with `qemu-img`
+	fn convert(&self, raw: &Path, disk: &PartitionLayout) -> Result<()> {
+		let out = &self.image;
+		let fmt = disk.format.qemu_name();
+		info!(?raw, ?out, fmt, "Converting disk image");
+
+		let mut args = vec![];
+		if let Some(compression) = disk.qcow2_compression {
+			if disk.format == DiskFormat::Qcow2 {
+				args.extend(["-c".to_string(), "-o".to_string()]);
+				args.push(format!("compression_type={}", compression.as_str()));
+			} else {
+				warn!(format = ?disk.format, "qcow2_compression only applies to qcow2 images, ignoring");
+			}
 		}
 
-		disk.unmount_from_chroot(chroot)?;
+		cmd_lib::run_cmd!(qemu-img convert -p -f raw -O $fmt $[args] $raw $out 2>&1)?;
+		Ok(())
+	}
 
-		drop(hdl);
+	/// Writes the dm-verity root hashes in `verity` to `<image>.roothash` and `<image>.usrhash`,
+	/// for pinning them without reading the boot entries
+	fn write_verity_hashes(&self, verity: &[String]) -> Result<()> {
+		for (kind, hash) in verity.iter().filter_map(|a| a.split_once("hash=")) {
+			if !["root", "usr"].contains(&kind) {
+				continue;
+			}
+			let mut path = self.image.clone().into_os_string();
+			path.push(format!(".{kind}hash"));
+			info!(?path, "Writing dm-verity root hash");
+			just_write(path, format!("{hash}\n"))?;
+		}
+		Ok(())
+	}
+
+	/// Generates `<image>.bmap` from the sparse map of the raw image with `bmaptool`
+	///
+	/// This has to run before compression, `bmaptool copy` finds the bmap of
+	/// `katsu.img.xz` at `katsu.img.bmap`.
+	fn bmap(&self) -> Result<()> {
+		let image = &self.image;
+		let mut bmap = image.clone().into_os_string();
+		bmap.push(".bmap");
+		info!(?bmap, "Generating bmap file");
+		cmd_lib::run_cmd!(bmaptool create -o $bmap $image 2>&1)?;
 		Ok(())
 	}
+
+	/// Compresses the finished image in place, returning the path of the compressed image
+	fn compress(&self, compression: ImageCompression) -> Result<PathBuf> {
+		let image = &self.image;
+		let mut out = image.clone().into_os_string();
+		out.push(format!(".{}", compression.ext()));
+		info!(?image, ?compression, "Compressing disk image");
+		match compression {
+			ImageCompression::Xz => cmd_lib::run_cmd!(xz -T0 -f $image 2>&1)?,
+			ImageCompression::Zstd => cmd_lib::run_cmd!(zstd -T0 --rm -f $image 2>&1)?,
+		}
+		Ok(out.into())
+	}
 }
 
 /// Installs directly to a device
-#[allow(dead_code)]
 pub struct DeviceInstaller {
 	pub device: PathBuf,
 	pub bootloader: Bootloader,
 	// root_builder
 	pub root_builder: Box<dyn RootBuilder>,
+	pub wipe: WipePolicy,
 }
 
 impl ImageBuilder for DeviceInstaller {
 	fn build(
-		&self, _chroot: &Path, _image: &Path, _manifest: &Manifest, _skip_phases: &SkipPhases,
+		&self, chroot: &Path, _image: &Path, manifest: &Manifest, _skip_phases: &SkipPhases,
 	) -> Result<()> {
-		todo!();
-		// self.root_builder.build(_chroot, _manifest)?;
-		// Ok(())
+		use std::os::unix::fs::FileTypeExt;
+
+		let device = &self.device;
+		if !fs::metadata(device)?.file_type().is_block_device() {
+			bail!("{device:?} is not a block device");
+		}
+
+		info!(?device, "Installing to device");
+		install_to_disk(
+			device,
+			chroot,
+			manifest,
+			&self.bootloader,
+			self.root_builder.as_ref(),
+			None,
+			self.wipe,
+		)?;
+		Ok(())
 	}
 }
 
@@ -982,8 +1959,7 @@ impl ImageBuilder for FsBuilder {
 	fn build(
 		&self, _chroot: &Path, _image: &Path, manifest: &Manifest, _skip_phases: &SkipPhases,
 	) -> Result<()> {
-		let out = manifest.out_file.as_ref().map_or("katsu-work/chroot", |s| s);
-		let out = Path::new(out);
+		let out = &manifest.get_out_file("katsu-work/chroot", "");
 		// check if image exists, and is a folder
 		if out.exists() && !out.is_dir() {
 			bail!("Image path is not a directory");
@@ -999,6 +1975,192 @@ impl ImageBuilder for FsBuilder {
 	}
 }
 
+/// Archives `chroot` into a tarball at `out`, keeping xattrs, SELinux labels, ACLs and hardlinks
+fn tar_rootfs(chroot: &Path, out: &Path, compression: Option<TarCompression>) -> Result<()> {
+	let comp = compression.map(|c| c.tar_flag());
+	info!(?chroot, ?out, ?compression, "Archiving root filesystem");
+	cmd_lib::run_cmd!(
+		tar --create --xattrs "--xattrs-include=*" --selinux --acls --numeric-owner --sparse $[comp]
+			"--exclude=./proc/*" "--exclude=./sys/*" "--exclude=./dev/*"
+			-f $out -C $chroot . 2>&1;
+	)?;
+	Ok(())
+}
+
+/// Archives the root tree into a tarball
+pub struct TarBuilder {
+	pub root_builder: Box<dyn RootBuilder>,
+}
+
+impl ImageBuilder for TarBuilder {
+	fn build(
+		&self, chroot: &Path, _: &Path, manifest: &Manifest, skip_phases: &SkipPhases,
+	) -> Result<()> {
+		crate::gen_phase!(skip_phases);
+
+		let compression = manifest.tar.as_ref().and_then(|t| t.compression);
+		let ext = compression.map_or("tar", |c| c.ext());
+		let out = manifest.get_out_file(&format!("out.{ext}"), ext);
+
+		phase!("root": self.root_builder.build(chroot, manifest));
+		phase!("tar": tar_rootfs(chroot, &out, compression));
+
+		info!(?out, "Tarball written");
+		Ok(())
+	}
+}
+
+/// Creates a single-layer OCI image out of the root tree
+///
+/// The result is either an [OCI image layout](https://github.com/opencontainers/image-spec/blob/main/image-layout.md)
+/// directory, or the same layout packed into an `oci-archive` tarball, both of which
+/// can be imported with `podman load` or `skopeo copy`.
+pub struct OciBuilder {
+	pub root_builder: Box<dyn RootBuilder>,
+}
+
+const OCI_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const OCI_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const OCI_LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+
+/// Hex encoded SHA-256 digest of the file at `path`
+fn sha256_file(path: &Path) -> Result<String> {
+	use sha2::Digest;
+	let mut hasher = sha2::Sha256::new();
+	std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+	Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Writes `data` to the layout's blob store, returning its digest and size
+fn write_oci_blob(blobs: &Path, data: &[u8]) -> Result<(String, usize)> {
+	use sha2::Digest;
+	let digest = format!("{:x}", sha2::Sha256::digest(data));
+	fs::write(blobs.join(&digest), data)?;
+	Ok((format!("sha256:{digest}"), data.len()))
+}
+
+impl OciBuilder {
+	/// Maps the target architecture to the GOARCH name used by OCI image configs
+	fn oci_arch(arch: &str) -> &str {
+		match arch {
+			"x86_64" => "amd64",
+			"aarch64" => "arm64",
+			"i386" | "i686" => "386",
+			"armv7l" | "armv7hl" => "arm",
+			arch => arch,
+		}
+	}
+
+	/// Writes an OCI image layout of `chroot` to `layout`
+	fn oci_layout(
+		&self, chroot: &Path, workspace: &Path, layout: &Path, manifest: &Manifest, oci: &OciConfig,
+	) -> Result<()> {
+		let blobs = layout.join("blobs/sha256");
+		fs::create_dir_all(&blobs)?;
+
+		// The config refers to the uncompressed layer, the manifest to the compressed one
+		let layer = workspace.join("oci-layer.tar");
+		tar_rootfs(chroot, &layer, None)?;
+		let diff_id = sha256_file(&layer)?;
+		cmd_lib::run_cmd!(gzip -n -f $layer 2>&1)?;
+		let layer = workspace.join("oci-layer.tar.gz");
+		let layer_digest = sha256_file(&layer)?;
+		let layer_size = fs::metadata(&layer)?.len();
+		fs::rename(&layer, blobs.join(&layer_digest))?;
+
+		let arch = manifest.dnf.arch.as_deref().unwrap_or(std::env::consts::ARCH);
+		let mut config = serde_json::json!({
+			"created": format!("{}T00:00:00Z", crate::util::today()),
+			"architecture": Self::oci_arch(arch),
+			"os": "linux",
+			"config": {
+				"Env": oci.env,
+				"Labels": oci.labels,
+			},
+			"rootfs": {
+				"type": "layers",
+				"diff_ids": [format!("sha256:{diff_id}")],
+			},
+		});
+		if !oci.entrypoint.is_empty() {
+			config["config"]["Entrypoint"] = serde_json::json!(oci.entrypoint);
+		}
+		if !oci.cmd.is_empty() {
+			config["config"]["Cmd"] = serde_json::json!(oci.cmd);
+		}
+		if let Some(workdir) = &oci.workdir {
+			config["config"]["WorkingDir"] = serde_json::json!(workdir);
+		}
+		if arch.starts_with("armv7") {
+			config["variant"] = serde_json::json!("v7");
+		}
+		let (config_digest, config_size) = write_oci_blob(&blobs, &serde_json::to_vec(&config)?)?;
+
+		let image_manifest = serde_json::json!({
+			"schemaVersion": 2,
+			"mediaType": OCI_MANIFEST_MEDIA_TYPE,
+			"config": {
+				"mediaType": OCI_CONFIG_MEDIA_TYPE,
+				"digest": config_digest,
+				"size": config_size,
+			},
+			"layers": [{
+				"mediaType": OCI_LAYER_MEDIA_TYPE,
+				"digest": format!("sha256:{layer_digest}"),
+				"size": layer_size,
+			}],
+		});
+		let (manifest_digest, manifest_size) =
+			write_oci_blob(&blobs, &serde_json::to_vec(&image_manifest)?)?;
+
+		let tag = oci.tag.as_deref().unwrap_or("latest");
+		let index = serde_json::json!({
+			"schemaVersion": 2,
+			"manifests": [{
+				"mediaType": OCI_MANIFEST_MEDIA_TYPE,
+				"digest": manifest_digest,
+				"size": manifest_size,
+				"annotations": { "org.opencontainers.image.ref.name": tag },
+			}],
+		});
+		fs::write(layout.join("index.json"), serde_json::to_vec(&index)?)?;
+		fs::write(layout.join("oci-layout"), r#"{"imageLayoutVersion":"1.0.0"}"#)?;
+
+		Ok(())
+	}
+}
+
+impl ImageBuilder for OciBuilder {
+	fn build(
+		&self, chroot: &Path, _: &Path, manifest: &Manifest, skip_phases: &SkipPhases,
+	) -> Result<()> {
+		crate::gen_phase!(skip_phases);
+
+		let oci = manifest.oci.clone().unwrap_or_default();
+		let ext = if oci.archive { "oci.tar" } else { "oci" };
+		let out = manifest.get_out_file(&format!("out.{ext}"), ext);
+		let workspace = chroot.parent().unwrap().to_path_buf();
+		if !oci.archive && out.exists() {
+			bail!("Output {out:?} already exists");
+		}
+
+		phase!("root": self.root_builder.build(chroot, manifest));
+
+		if oci.archive {
+			let layout = workspace.join("oci-layout");
+			let _ = fs::remove_dir_all(&layout);
+			phase!("oci": self.oci_layout(chroot, &workspace, &layout, manifest, &oci));
+			phase!("archive": cmd_lib::run_cmd!(tar --create -f $out -C $layout . 2>&1));
+			fs::remove_dir_all(&layout)?;
+		} else {
+			phase!("oci": self.oci_layout(chroot, &workspace, &out, manifest, &oci));
+		}
+
+		info!(?out, "OCI image written");
+		Ok(())
+	}
+}
+
 pub struct IsoBuilder {
 	pub bootloader: Bootloader,
 	pub root_builder: Box<dyn RootBuilder>,
@@ -1139,7 +2301,7 @@ impl IsoBuilder {
 		let efiboot = tree.join("boot/efiboot.img");
 
 		match self.bootloader {
-			Bootloader::Grub => {
+			Bootloader::Grub | Bootloader::GrubHybrid => {
 				// cmd_lib::run_cmd!(grub2-mkrescue -o $image $tree -volid $volid 2>&1)?;
 				// todo: normal xorriso command does not work for some reason, errors out with some GPT partition shenanigans
 				// todo: maybe we need to replicate mkefiboot? (see lorax/efiboot)
@@ -1196,7 +2358,33 @@ impl IsoBuilder {
 					.arg(image)
 					.status()?;
 			},
-			Bootloader::REFInd => {
+			Bootloader::GrubBios => {
+				// El Torito only, no EFI partition
+				std::process::Command::new("xorrisofs")
+					.args(["-iso-level", "3"])
+					.arg("-R")
+					.arg("-V")
+					.arg(&volid)
+					.arg("--grub2-mbr")
+					.arg(&grub2_mbr_hybrid)
+					.arg("-c")
+					.arg("boot.cat")
+					.arg("--boot-catalog-hide")
+					.arg("-b")
+					.arg(bios_bin)
+					.arg("-no-emul-boot")
+					.arg("-boot-load-size")
+					.arg("4")
+					.arg("-boot-info-table")
+					.arg("--grub2-boot-info")
+					.arg("-vvvvv")
+					.arg("--md5")
+					.arg(&tree)
+					.arg("-o")
+					.arg(image)
+					.status()?;
+			},
+			Bootloader::REFInd | Bootloader::SystemdBoot => {
 				std::process::Command::new("xorriso")
 					.arg("-as")
 					.arg("mkisofs")
@@ -1278,7 +2466,14 @@ impl ImageBuilder for IsoBuilder {
 		crate::gen_phase!(skip_phases);
 		// You can now skip phases by adding environment variable `KATSU_SKIP_PHASES` with a comma-separated list of phases to skip
 
-		let image = PathBuf::from(manifest.out_file.as_ref().map_or("out.iso", |s| s));
+		// only the UEFI payload on efiboot.img gets signed, these never build one
+		if manifest.secureboot.is_some()
+			&& matches!(self.bootloader, Bootloader::Limine | Bootloader::GrubBios)
+		{
+			bail!("Secure Boot signing is not supported for {:?} ISOs", self.bootloader);
+		}
+
+		let image = manifest.get_out_file("out.iso", "iso");
 		// Create workspace directory
 		let workspace = chroot.parent().unwrap().to_path_buf();
 		debug!("Workspace: {workspace:#?}");
@@ -1300,6 +2495,22 @@ impl ImageBuilder for IsoBuilder {
 		}
 
 		phase!("copy-live": self.bootloader.copy_liveos(manifest, chroot));
+
+		if let Some(secureboot) = &manifest.secureboot {
+			let iso_tree = workspace.join(ISO_TREE);
+			phase!("sb-verify": {
+				let efi = iso_tree.join("EFI");
+				if efi.exists() {
+					secureboot.verify_tree(&efi)?;
+				} else {
+					warn!(?efi, "No UEFI boot files to verify");
+				}
+				match iso_tree.join("boot/vmlinuz") {
+					kernel if kernel.exists() => secureboot.verify(&kernel),
+					_ => Ok(()),
+				}
+			});
+		}
 		// Reduce storage overhead by removing the original chroot
 		// However, we'll keep an env flag to keep the chroot for debugging purposes
 		if !feature_flag_bool!("keep-chroot")
@@ -1331,9 +2542,16 @@ pub struct KatsuBuilder {
 }
 
 impl KatsuBuilder {
+	/// `target` is the block device to install to, only used for [`OutputFormat::Device`]
 	pub fn new(
-		manifest: Manifest, output_format: OutputFormat, skip_phases: SkipPhases,
+		mut manifest: Manifest, output_format: OutputFormat, skip_phases: SkipPhases,
+		target: Option<PathBuf>, wipe: WipePolicy,
 	) -> Result<Self> {
+		if let Some(disk) = manifest.disk.as_mut() {
+			disk.add_verity_partitions()?;
+		}
+		crate::util::set_templates(&manifest)?;
+
 		let root_builder = match manifest.builder.as_ref().expect("Builder unspecified").as_str() {
 			"dnf" => Box::new(manifest.dnf.clone()) as Box<dyn RootBuilder>,
 			"bootc" => Box::new(manifest.bootc.clone()) as Box<dyn RootBuilder>,
@@ -1349,12 +2567,22 @@ impl KatsuBuilder {
 			OutputFormat::DiskImage => Box::new(DiskImageBuilder {
 				bootloader,
 				root_builder,
-				image: PathBuf::from("./katsu-work/image/katsu.img"),
+				wipe,
+				image: {
+					let ext = manifest.disk.as_ref().map_or(DiskFormat::Raw, |d| d.format).ext();
+					manifest.get_out_file(&format!("./katsu-work/image/katsu.{ext}"), ext)
+				},
 			}) as Box<dyn ImageBuilder>,
+			OutputFormat::Device => {
+				bail_let!(Some(device) = target => "Target device not specified, use --target");
+				Box::new(DeviceInstaller { bootloader, root_builder, device, wipe })
+					as Box<dyn ImageBuilder>
+			},
 			OutputFormat::Folder => {
 				Box::new(FsBuilder { bootloader, root_builder }) as Box<dyn ImageBuilder>
 			},
-			_ => todo!(),
+			OutputFormat::Tar => Box::new(TarBuilder { root_builder }) as Box<dyn ImageBuilder>,
+			OutputFormat::Oci => Box::new(OciBuilder { root_builder }) as Box<dyn ImageBuilder>,
 		};
 
 		Ok(Self { image_builder, manifest, skip_phases })
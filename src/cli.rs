@@ -1,11 +1,16 @@
 use std::{path::PathBuf, sync::Mutex};
 
 use clap::{value_parser, Parser, ValueEnum};
-use color_eyre::Result;
+use color_eyre::{eyre::bail, Result};
 use serde_derive::{Deserialize, Serialize};
 use tracing::trace;
 
-use crate::{builder::KatsuBuilder, config::Manifest};
+use crate::{
+	bail_let,
+	builder::KatsuBuilder,
+	config::{DiskFormat, ImageCompression, Manifest},
+	util::WipePolicy,
+};
 
 static CLI_MUTEX: Mutex<Option<KatsuCli>> = Mutex::new(None);
 
@@ -46,8 +51,37 @@ pub struct KatsuCli {
 
 	#[arg(long, short = 'O')]
 	/// Override output file location
+	///
+	/// Supports the `{distro}`, `{arch}`, `{releasever}`, `{date}` and `{ext}` placeholders,
+	/// e.g. `{distro}-{arch}-{date}.{ext}`
 	output_file: Option<PathBuf>,
 
+	#[arg(long, value_enum)]
+	/// Override the disk image format (`disk.format` in the manifest)
+	disk_format: Option<DiskFormat>,
+
+	#[arg(long, value_enum)]
+	/// Compress the finished disk image (`disk.compression` in the manifest)
+	disk_compression: Option<ImageCompression>,
+
+	#[arg(long, default_value = "false")]
+	/// Generate a bmap file for the disk image (`disk.bmap` in the manifest)
+	bmap: bool,
+
+	#[arg(long, short = 't')]
+	/// Block device to install to when using the `device` output format
+	///
+	/// WARNING: Everything on the device will be erased
+	target: Option<PathBuf>,
+
+	#[arg(long, default_value = "false")]
+	/// Allow wiping devices which are not removable
+	force: bool,
+
+	#[arg(long, short = 'y', default_value = "false")]
+	/// Do not ask for confirmation before wiping a device
+	yes: bool,
+
 	/// Katsu feature flags, comma separated
 	#[arg(
 		long,
@@ -92,6 +126,10 @@ pub enum OutputFormat {
 	Device,
 	/// Simply copies the root tree to a directory
 	Folder,
+	/// Archives the root tree into a tarball, optionally compressed
+	Tar,
+	/// Creates a single-layer OCI image layout or `oci-archive` out of the root tree
+	Oci,
 }
 
 impl std::str::FromStr for OutputFormat {
@@ -103,6 +141,8 @@ impl std::str::FromStr for OutputFormat {
 			"device" => Ok(OutputFormat::Device),
 			"folder" => Ok(OutputFormat::Folder),
 			"fs" => Ok(OutputFormat::Folder),
+			"tar" => Ok(OutputFormat::Tar),
+			"oci" => Ok(OutputFormat::Oci),
 			_ => Err(format!("{s} is not a valid output format")),
 		}
 	}
@@ -135,9 +175,38 @@ pub fn parse(cli: KatsuCli) -> Result<()> {
 		manifest.out_file = Some(output_file.into_os_string().into_string().unwrap());
 	}
 
+	if let Some(format) = cli.disk_format {
+		bail_let!(Some(disk) = manifest.disk.as_mut() => "--disk-format requires a disk layout in the manifest");
+		disk.format = format;
+	}
+
+	if let Some(compression) = cli.disk_compression {
+		bail_let!(Some(disk) = manifest.disk.as_mut() => "--disk-compression requires a disk layout in the manifest");
+		disk.compression = Some(compression);
+	}
+
+	if cli.bmap {
+		bail_let!(Some(disk) = manifest.disk.as_mut() => "--bmap requires a disk layout in the manifest");
+		disk.bmap = true;
+	}
+
+	if cli.target.is_some() && !matches!(cli.output, OutputFormat::Device) {
+		bail!(
+			"--target is only used with the `device` output format, use --output-file for images"
+		);
+	}
+
 	trace!(?manifest, "Loaded manifest");
 
-	let builder = KatsuBuilder::new(manifest, cli.output, cli.skip_phases.unwrap_or_default())?;
+	let wipe = WipePolicy { force: cli.force, yes: cli.yes };
+
+	let builder = KatsuBuilder::new(
+		manifest,
+		cli.output,
+		cli.skip_phases.unwrap_or_default(),
+		cli.target,
+		wipe,
+	)?;
 
 	tracing::info!("Building image");
 	builder.build()?;
@@ -1,6 +1,12 @@
-use crate::{builder::Bootloader, cli::OutputFormat, util::enter_chroot_run};
+use crate::{
+	bail_let,
+	builder::Bootloader,
+	cli::OutputFormat,
+	util::{enter_chroot_run, WipePolicy},
+};
 use bytesize::ByteSize;
-use color_eyre::Result;
+use clap::ValueEnum;
+use color_eyre::{eyre::bail, Result};
 use serde::Deserialize;
 use serde_derive::{Deserialize, Serialize};
 use std::{
@@ -29,6 +35,525 @@ impl IsoConfig {
 	}
 }
 
+/// Compression for tarball outputs
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TarCompression {
+	Gzip,
+	Xz,
+	Zstd,
+}
+
+impl TarCompression {
+	/// File extension of the compressed tarball
+	pub fn ext(&self) -> &'static str {
+		match self {
+			Self::Gzip => "tar.gz",
+			Self::Xz => "tar.xz",
+			Self::Zstd => "tar.zst",
+		}
+	}
+
+	/// `tar(1)` flag selecting the compression program
+	pub fn tar_flag(&self) -> &'static str {
+		match self {
+			Self::Gzip => "--gzip",
+			Self::Xz => "--xz",
+			Self::Zstd => "--zstd",
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct TarConfig {
+	/// Compression for the tarball, uncompressed if not set
+	#[serde(default)]
+	pub compression: Option<TarCompression>,
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct OciConfig {
+	/// Write a single `oci-archive` tarball instead of an OCI image layout directory
+	#[serde(default)]
+	pub archive: bool,
+	/// Reference name of the image inside the layout, defaults to `latest`
+	#[serde(default)]
+	pub tag: Option<String>,
+	#[serde(default)]
+	pub entrypoint: Vec<String>,
+	#[serde(default)]
+	pub cmd: Vec<String>,
+	/// Environment variables, in `KEY=value` form
+	#[serde(default)]
+	pub env: Vec<String>,
+	#[serde(default)]
+	pub workdir: Option<String>,
+	#[serde(default)]
+	pub labels: BTreeMap<String, String>,
+}
+
+/// Tool to assemble a Unified Kernel Image with
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UkiTool {
+	/// `ukify build` from systemd
+	Ukify,
+	/// Adds the sections to the systemd EFI stub from the chroot by hand
+	Objcopy,
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct UkiConfig {
+	/// Defaults to `ukify` if it is installed on the host, `objcopy` otherwise
+	#[serde(default)]
+	pub tool: Option<UkiTool>,
+	/// Boot splash image in BMP format, relative to the manifest
+	#[serde(default)]
+	pub splash: Option<PathBuf>,
+}
+
+/// U-Boot on ARM boards, booting the installed system through `/boot/extlinux/extlinux.conf`
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct UBootConfig {
+	/// Device tree to boot with, relative to the kernel's dtb directory,
+	/// e.g. `rockchip/rk3399-rockpro64.dtb`. U-Boot picks one by itself if unset
+	#[serde(default)]
+	pub fdt: Option<String>,
+	/// Board specific SPL and U-Boot images written to the disk outside of the partitions
+	#[serde(default)]
+	pub blobs: Vec<UBootBlob>,
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct UBootBlob {
+	/// Image on the host, relative to the manifest,
+	/// e.g. `/usr/share/uboot/rockpro64-rk3399/idbloader.img`
+	pub file: PathBuf,
+	/// Offset from the start of the disk
+	pub offset: ByteSize,
+}
+
+impl UBootConfig {
+	/// Writes the blobs to `disk` partitioned with `layout` and `label`, refusing to overwrite
+	/// the partition table or any partition with a filesystem
+	pub fn write_blobs(
+		&self, disk: &Path, layout: &PartitionLayout, label: DiskLabel,
+	) -> Result<()> {
+		if self.blobs.is_empty() {
+			return Ok(());
+		}
+		let disk_size = cmd_lib::run_fun!(blockdev --getsize64 $disk)?.trim().parse()?;
+		// NR START END, in 512 byte sectors
+		let parts = cmd_lib::run_fun!(partx -g -o NR,START,END $disk)?;
+		let parts = parts
+			.lines()
+			.map(|l| {
+				let cols = l.split_whitespace().map(str::parse).collect::<Result<Vec<u64>, _>>()?;
+				bail_let!([nr, start, end] = cols[..] => "Cannot parse partx output: {l}");
+				Ok((nr as usize, start * 512, (end + 1) * 512))
+			})
+			.collect::<Result<Vec<_>>>()?;
+
+		for UBootBlob { file, offset } in &self.blobs {
+			let (start, end) = (offset.as_u64(), offset.as_u64() + fs::metadata(file)?.len());
+			let usable = label.usable(disk_size);
+			if start < usable.start || end > usable.end {
+				bail!("U-Boot blob {file:?} at {offset} would overwrite the partition table");
+			}
+			let used = parts.iter().find(|(nr, pstart, pend)| {
+				start < *pend
+					&& end > *pstart
+					&& layout.partitions.get(nr - 1).is_some_and(|p| p.filesystem != "none")
+			});
+			if let Some((nr, ..)) = used {
+				bail!("U-Boot blob {file:?} at {offset} overlaps partition {nr}, use a partition with `filesystem: none` to reserve space for it");
+			}
+
+			info!(?file, %offset, "Writing U-Boot blob");
+			let offset = offset.as_u64();
+			cmd_lib::run_cmd!(dd if=$file of=$disk bs=1M seek=$offset oflag=seek_bytes conv=notrunc,fsync 2>&1)?;
+		}
+		Ok(())
+	}
+}
+
+/// Raspberry Pi firmware booting the kernel directly from the FAT firmware partition
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct RaspberryPiConfig {
+	/// Extra `config.txt` settings, e.g. `disable_overscan: 1`
+	#[serde(default)]
+	pub config: BTreeMap<String, String>,
+	/// Device tree overlays to load, e.g. `vc4-kms-v3d`
+	#[serde(default)]
+	pub dtoverlays: Vec<String>,
+	/// Extra `cmdline.txt` arguments, in addition to `kernel_cmdline`
+	#[serde(default)]
+	pub cmdline: Option<String>,
+}
+
+/// Boot menu of live images
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct BootConfig {
+	/// Boot menu entries, a normal, a media check and a nomodeset entry if empty
+	#[serde(default)]
+	pub entries: Vec<BootEntry>,
+	/// Seconds to show the boot menu for, 60 if unset
+	#[serde(default)]
+	pub timeout: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct BootEntry {
+	/// Title in the boot menu, `{distro}` is replaced with the distro name
+	pub title: String,
+	/// Kernel command line arguments for this entry, in addition to the ones needed to boot
+	#[serde(default)]
+	pub cmdline: String,
+	/// Boot this entry by default, otherwise the first entry is the default
+	#[serde(default)]
+	pub default: bool,
+}
+
+impl BootEntry {
+	pub fn new(title: &str, cmdline: &str) -> Self {
+		Self { title: title.to_string(), cmdline: cmdline.to_string(), default: false }
+	}
+}
+
+impl BootConfig {
+	pub fn timeout(&self) -> u32 {
+		self.timeout.unwrap_or(60)
+	}
+
+	/// The boot menu entries with their titles expanded, and the index of the default one
+	pub fn live_entries(&self, distro: &str) -> (Vec<BootEntry>, usize) {
+		let entries = if self.entries.is_empty() {
+			vec![
+				BootEntry::new("{distro}", "enforcing=0"),
+				BootEntry::new("{distro} (Check Image)", "rd.live.check enforcing=0"),
+				BootEntry::new("{distro} (nomodeset)", "enforcing=0 nomodeset"),
+			]
+		} else {
+			self.entries.clone()
+		};
+		let default = entries.iter().position(|e| e.default).unwrap_or(0);
+		let entries = entries
+			.into_iter()
+			.map(|e| BootEntry { title: e.title.replace("{distro}", distro), ..e })
+			.collect();
+		(entries, default)
+	}
+}
+
+#[test]
+fn test_live_entries() {
+	let (entries, default) = BootConfig::default().live_entries("Ultramarine Linux");
+	assert_eq!(entries.len(), 3);
+	assert_eq!(entries[1].title, "Ultramarine Linux (Check Image)");
+	assert_eq!(default, 0);
+
+	let boot: BootConfig = serde_yaml::from_str(
+		r#"
+timeout: 5
+entries:
+  - title: "{distro}"
+  - title: Install {distro}
+    cmdline: inst.stage2=hd:LABEL=install
+    default: true
+  - title: Rescue
+    cmdline: systemd.unit=rescue.target
+"#,
+	)
+	.unwrap();
+	let (entries, default) = boot.live_entries("Katsu");
+	assert_eq!(
+		entries[1],
+		BootEntry {
+			title: "Install Katsu".to_string(),
+			cmdline: "inst.stage2=hd:LABEL=install".to_string(),
+			default: true
+		}
+	);
+	assert_eq!(default, 1);
+	assert_eq!(boot.timeout(), 5);
+}
+
+/// Boot menu and splash theming, file paths are relative to the manifest
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct ThemeConfig {
+	#[serde(default)]
+	pub grub: GrubTheme,
+	#[serde(default)]
+	pub limine: LimineTheme,
+	#[serde(default)]
+	pub refind: RefindTheme,
+	/// Plymouth theme to set in the chroot, it must be installed by a package
+	#[serde(default)]
+	pub plymouth: Option<String>,
+}
+
+impl ThemeConfig {
+	fn files(&mut self) -> [&mut Option<PathBuf>; 6] {
+		[
+			&mut self.grub.theme,
+			&mut self.grub.background,
+			&mut self.grub.font,
+			&mut self.limine.wallpaper,
+			&mut self.refind.icons,
+			&mut self.refind.banner,
+		]
+	}
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct GrubTheme {
+	/// Theme directory containing a `theme.txt`
+	#[serde(default)]
+	pub theme: Option<PathBuf>,
+	/// Background image in PNG, JPEG or TGA format
+	#[serde(default)]
+	pub background: Option<PathBuf>,
+	/// Font in GRUB's PF2 format
+	#[serde(default)]
+	pub font: Option<PathBuf>,
+}
+
+impl GrubTheme {
+	pub fn is_empty(&self) -> bool {
+		self.theme.is_none() && self.background.is_none() && self.font.is_none()
+	}
+
+	/// Copies the theme files to `grub_dir`, returning the theme with paths relative to it
+	pub fn install(&self, grub_dir: &Path) -> Result<Self> {
+		let theme = if let Some(theme) = &self.theme {
+			bail_let!(Some(name) = theme.file_name() => "Invalid GRUB theme directory {theme:?}");
+			if !theme.join("theme.txt").exists() {
+				bail!("GRUB theme {theme:?} has no theme.txt");
+			}
+			let themes = grub_dir.join("themes");
+			std::fs::create_dir_all(&themes)?;
+			cmd_lib::run_cmd!(cp -r $theme $themes 2>&1)?;
+			Some(Path::new("themes").join(name).join("theme.txt"))
+		} else {
+			None
+		};
+		Ok(Self {
+			theme,
+			background: (self.background.as_deref())
+				.map(|f| copy_theme_file(f, grub_dir, ""))
+				.transpose()?,
+			font: self
+				.font
+				.as_deref()
+				.map(|f| copy_theme_file(f, grub_dir, "fonts"))
+				.transpose()?,
+		})
+	}
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct LimineTheme {
+	/// Wallpaper image in BMP, PNG or JPEG format
+	#[serde(default)]
+	pub wallpaper: Option<PathBuf>,
+	/// Terminal background color, as `TTRRGGBB` hex
+	#[serde(default)]
+	pub background: Option<String>,
+	/// Terminal foreground color, as `RRGGBB` hex
+	#[serde(default)]
+	pub foreground: Option<String>,
+}
+
+impl LimineTheme {
+	/// Copies the wallpaper to `boot_dir`, returning the theme with paths relative to it
+	pub fn install(&self, boot_dir: &Path) -> Result<Self> {
+		Ok(Self {
+			wallpaper: (self.wallpaper.as_deref())
+				.map(|f| copy_theme_file(f, boot_dir, ""))
+				.transpose()?,
+			..self.clone()
+		})
+	}
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct RefindTheme {
+	/// Directory of icons replacing rEFInd's default ones
+	#[serde(default)]
+	pub icons: Option<PathBuf>,
+	/// Banner image in PNG or BMP format
+	#[serde(default)]
+	pub banner: Option<PathBuf>,
+}
+
+impl RefindTheme {
+	/// Copies the icons and banner to `refind_dir`, returning the theme with paths relative to it
+	pub fn install(&self, refind_dir: &Path) -> Result<Self> {
+		if let Some(icons) = &self.icons {
+			cmd_lib::run_cmd!(cp -rv $icons/. $refind_dir/icons/ 2>&1)?;
+		}
+		Ok(Self {
+			icons: self.icons.as_ref().map(|_| PathBuf::from("icons")),
+			banner: self
+				.banner
+				.as_deref()
+				.map(|f| copy_theme_file(f, refind_dir, ""))
+				.transpose()?,
+		})
+	}
+}
+
+/// Copies `file` to `dir/subdir`, returning its path relative to `dir`
+fn copy_theme_file(file: &Path, dir: &Path, subdir: &str) -> Result<PathBuf> {
+	bail_let!(Some(name) = file.file_name() => "Invalid theme file {file:?}");
+	let rel = Path::new(subdir).join(name);
+	std::fs::create_dir_all(dir.join(subdir))?;
+	std::fs::copy(file, dir.join(&rel))?;
+	Ok(rel)
+}
+
+/// Secure Boot signing with a local key pair
+///
+/// Test keys can be generated with
+/// `openssl req -new -x509 -newkey rsa:2048 -nodes -days 3650 -subj "/CN=Katsu test key/" -keyout katsu.key -out katsu.crt`
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct SecureBootConfig {
+	/// PEM private key, relative to the manifest
+	pub key: PathBuf,
+	/// PEM certificate of `key`, relative to the manifest
+	pub cert: PathBuf,
+	/// Put the certificate on the ESP for enrollment, as DER for `mokutil --import` and
+	/// as signed PK, KEK and db lists for systemd-boot's automatic enrollment
+	#[serde(default)]
+	pub enroll: bool,
+}
+
+/// Name of the directories on the ESP holding the Secure Boot enrollment bundle
+const SECUREBOOT_KEYS: &str = "katsu";
+
+impl SecureBootConfig {
+	/// Whether `file` is already signed by Microsoft, like shim, and has to stay that way
+	fn is_microsoft_signed(file: &Path) -> bool {
+		cmd_lib::run_fun!(sbverify --list $file 2>&1).is_ok_and(|out| out.contains("Microsoft"))
+	}
+
+	/// Signs `file` in place
+	pub fn sign(&self, file: &Path) -> Result<()> {
+		let (key, cert) = (&self.key, &self.cert);
+		debug!(?file, "Signing for Secure Boot");
+		cmd_lib::run_cmd!(sbsign --key $key --cert $cert --output $file $file 2>&1)?;
+		Ok(())
+	}
+
+	/// Checks the signature of `file` against the certificate
+	pub fn verify(&self, file: &Path) -> Result<()> {
+		let cert = &self.cert;
+		if cmd_lib::run_cmd!(sbverify --cert $cert $file 2>&1).is_err() {
+			bail!("{file:?} is not signed with the Secure Boot certificate {cert:?}");
+		}
+		Ok(())
+	}
+
+	/// Signs all EFI binaries below `dir`, except those signed by Microsoft
+	pub fn sign_tree(&self, dir: &Path) -> Result<()> {
+		for file in efi_binaries(dir)? {
+			if Self::is_microsoft_signed(&file) {
+				debug!(?file, "Already signed by Microsoft, skipping");
+				continue;
+			}
+			self.sign(&file)?;
+		}
+		Ok(())
+	}
+
+	/// Checks the signatures of all EFI binaries below `dir`, except those signed by Microsoft
+	pub fn verify_tree(&self, dir: &Path) -> Result<()> {
+		for file in efi_binaries(dir)? {
+			if !Self::is_microsoft_signed(&file) {
+				self.verify(&file)?;
+			}
+		}
+		info!(?dir, "Secure Boot signatures verified");
+		Ok(())
+	}
+
+	/// Kernels in the chroot, in `/boot` and next to their modules
+	pub fn kernels(chroot: &Path) -> Result<Vec<PathBuf>> {
+		let mut kernels = vec![];
+		for entry in fs::read_dir(chroot.join("boot"))? {
+			let path = entry?.path();
+			if path.file_name().is_some_and(|n| n.to_string_lossy().starts_with("vmlinuz-")) {
+				kernels.push(path);
+			}
+		}
+		for entry in fs::read_dir(chroot.join("usr/lib/modules"))? {
+			let path = entry?.path().join("vmlinuz");
+			if path.exists() {
+				kernels.push(path);
+			}
+		}
+		Ok(kernels)
+	}
+
+	/// Writes the certificate to `esp` for enrolling it on the target machine
+	pub fn write_enrollment(&self, esp: &Path) -> Result<()> {
+		let (key, cert) = (&self.key, &self.cert);
+		info!(?esp, "Writing Secure Boot enrollment bundle");
+
+		let mok = esp.join("EFI").join(SECUREBOOT_KEYS);
+		fs::create_dir_all(&mok)?;
+		let der = mok.join("secureboot.cer");
+		cmd_lib::run_cmd!(openssl x509 -in $cert -outform DER -out $der 2>&1)?;
+
+		// systemd-boot enrolls these by itself when the firmware is in setup mode
+		let keys = esp.join("loader/keys").join(SECUREBOOT_KEYS);
+		fs::create_dir_all(&keys)?;
+		// derived from the certificate and timestamped with SOURCE_DATE_EPOCH if set,
+		// so identical inputs give an identical ESP
+		let guid = uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_OID, &fs::read(&der)?).to_string();
+		let timestamp = crate::util::source_date_epoch()
+			.map_or(vec![], |secs| vec!["-t".to_string(), crate::util::ctime(secs)]);
+		let esl = keys.join("katsu.esl");
+		cmd_lib::run_cmd!(cert-to-efi-sig-list -g $guid $cert $esl 2>&1)?;
+		for var in ["PK", "KEK", "db"] {
+			let auth = keys.join(format!("{var}.auth"));
+			let timestamp = timestamp.clone();
+			cmd_lib::run_cmd!(sign-efi-sig-list $[timestamp] -g $guid -k $key -c $cert $var $esl $auth 2>&1)?;
+		}
+		fs::remove_file(esl)?;
+		Ok(())
+	}
+}
+
+/// All `.efi` files below `dir`, case insensitive
+fn efi_binaries(dir: &Path) -> Result<Vec<PathBuf>> {
+	let mut files = vec![];
+	for entry in fs::read_dir(dir)? {
+		let path = entry?.path();
+		if path.is_dir() {
+			files.extend(efi_binaries(&path)?);
+		} else if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("efi")) {
+			files.push(path);
+		}
+	}
+	files.sort();
+	Ok(files)
+}
+
+#[test]
+fn test_efi_binaries() {
+	let dir = std::env::temp_dir().join(format!("katsu-test-efi-{}", std::process::id()));
+	fs::create_dir_all(dir.join("EFI/BOOT")).unwrap();
+	fs::create_dir_all(dir.join("EFI/Linux")).unwrap();
+	for file in ["EFI/BOOT/BOOTX64.EFI", "EFI/Linux/fedora-6.8.efi", "EFI/BOOT/grub.cfg"] {
+		fs::write(dir.join(file), "").unwrap();
+	}
+
+	let files = efi_binaries(&dir).unwrap();
+	fs::remove_dir_all(&dir).unwrap();
+	assert_eq!(files, [dir.join("EFI/BOOT/BOOTX64.EFI"), dir.join("EFI/Linux/fedora-6.8.efi")]);
+}
+
 #[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct Manifest {
 	pub builder: Option<String>,
@@ -66,11 +591,51 @@ pub struct Manifest {
 	/// Extra parameters to the kernel command line in bootloader configs
 	pub kernel_cmdline: Option<String>,
 
+	/// Boot menu entries and timeout
+	#[serde(default)]
+	pub boot: BootConfig,
+
+	/// Boot menu and splash theming
+	#[serde(default)]
+	pub theme: ThemeConfig,
+
+	/// Directory of Tera templates overriding the built-in ones by file name,
+	/// e.g. `grub.cfg.tera` or `fstab.tera`. The manifest is available to them as `manifest`.
+	#[serde(default)]
+	pub templates: Option<PathBuf>,
+
 	/// ISO config (optional)
 	/// This is only used for ISO images
 	#[serde(default)]
 	pub iso: Option<IsoConfig>,
 
+	/// Tarball config (optional)
+	/// This is only used for tarball outputs
+	#[serde(default)]
+	pub tar: Option<TarConfig>,
+
+	/// OCI image config (optional)
+	/// This is only used for OCI image outputs
+	#[serde(default)]
+	pub oci: Option<OciConfig>,
+
+	/// Sign EFI binaries and kernels for Secure Boot (optional)
+	#[serde(default)]
+	pub secureboot: Option<SecureBootConfig>,
+
+	/// Build a Unified Kernel Image (optional)
+	/// Disk images get it in `EFI/Linux/` on the ESP, ISOs only with systemd-boot
+	#[serde(default)]
+	pub uki: Option<UkiConfig>,
+
+	/// U-Boot settings for disk images booted with `bootloader: uboot`
+	#[serde(default)]
+	pub uboot: Option<UBootConfig>,
+
+	/// Raspberry Pi firmware settings for disk images booted with `bootloader: raspberrypi`
+	#[serde(default)]
+	pub raspberrypi: RaspberryPiConfig,
+
 	// deserialize with From<&str>
 	#[serde(default, deserialize_with = "deseralize_bootloader")]
 	pub bootloader: Bootloader,
@@ -94,6 +659,37 @@ impl Manifest {
 			DEFAULT_VOLID.to_string()
 		}
 	}
+
+	/// Get the output path, falling back to `default` if `out_file` is unset
+	///
+	/// Naming template placeholders are expanded, see [`Manifest::expand_name`].
+	pub fn get_out_file(&self, default: &str, ext: &str) -> PathBuf {
+		let name = self.out_file.as_deref().unwrap_or(default);
+		PathBuf::from(self.expand_name(name, ext, &crate::util::today()))
+	}
+
+	/// Expands naming template placeholders in `name`
+	///
+	/// Supported placeholders are `{distro}`, `{arch}`, `{releasever}`, `{date}` and `{ext}`,
+	/// so `{distro}-{arch}-{date}.{ext}` becomes `Ultramarine-x86_64-2024-01-01.iso`.
+	/// If the output has no extension, `.{ext}` is dropped entirely. `{date}` becomes `date`.
+	pub fn expand_name(&self, name: &str, ext: &str, date: &str) -> String {
+		let distro =
+			self.distro.as_deref().unwrap_or("katsu").split_whitespace().collect::<Vec<_>>();
+		let arch = self.dnf.arch.as_deref().unwrap_or(std::env::consts::ARCH);
+		let name = if ext.is_empty() { name.replace(".{ext}", "") } else { name.to_string() };
+
+		[
+			("{distro}", distro.join("-")),
+			("{arch}", arch.to_string()),
+			("{releasever}", self.dnf.releasever.clone()),
+			("{date}", date.to_string()),
+			("{ext}", ext.to_string()),
+		]
+		.iter()
+		.fold(name, |name, (k, v)| name.replace(k, v))
+	}
+
 	/// Loads a single manifest from a file
 	pub fn load(path: &Path) -> Result<Self> {
 		let mut manifest: Self = serde_yaml::from_str(&std::fs::read_to_string(path)?)?;
@@ -141,6 +737,58 @@ impl Manifest {
 			}
 		}
 
+		// key files of encrypted partitions are relative to the manifest too
+		let encryptions = manifest.disk.iter_mut().flat_map(|d| &mut d.partitions);
+		for key_file in encryptions.filter_map(|p| p.encryption.as_mut()?.key_file.as_mut()) {
+			let key_file_can = path_can.join(&key_file);
+			if !key_file_can.exists() {
+				return Err(path_not_exists_error(&key_file_can));
+			}
+			*key_file = key_file_can.canonicalize()?;
+		}
+
+		if let Some(secureboot) = &mut manifest.secureboot {
+			for file in [&mut secureboot.key, &mut secureboot.cert] {
+				let file_can = path_can.join(&file);
+				if !file_can.exists() {
+					return Err(path_not_exists_error(&file_can));
+				}
+				*file = file_can.canonicalize()?;
+			}
+		}
+
+		for blob in manifest.uboot.iter_mut().flat_map(|u| &mut u.blobs) {
+			let file_can = path_can.join(&blob.file);
+			if !file_can.exists() {
+				return Err(path_not_exists_error(&file_can));
+			}
+			blob.file = file_can.canonicalize()?;
+		}
+
+		if let Some(splash) = manifest.uki.as_mut().and_then(|u| u.splash.as_mut()) {
+			let splash_can = path_can.join(&splash);
+			if !splash_can.exists() {
+				return Err(path_not_exists_error(&splash_can));
+			}
+			*splash = splash_can.canonicalize()?;
+		}
+
+		for file in manifest.theme.files().into_iter().flatten() {
+			let file_can = path_can.join(&file);
+			if !file_can.exists() {
+				return Err(path_not_exists_error(&file_can));
+			}
+			*file = file_can.canonicalize()?;
+		}
+
+		if let Some(templates) = &mut manifest.templates {
+			let templates_can = path_can.join(&templates);
+			if !templates_can.is_dir() {
+				return Err(path_not_exists_error(&templates_can));
+			}
+			*templates = templates_can.canonicalize()?;
+		}
+
 		//  canonicalize repodir if it exists, relative to the file that imported it
 		if let Some(repodir) = &mut manifest.dnf.repodir {
 			// check if path even exists
@@ -163,6 +811,8 @@ impl Manifest {
 		let bootloader = take(&mut manifest.bootloader);
 		let iso = take(&mut manifest.iso);
 		let disk = take(&mut manifest.disk);
+		let tar = take(&mut manifest.tar);
+		let oci = take(&mut manifest.oci);
 
 		let mut dnf = take(&mut manifest.dnf);
 		// everything but the package lists
@@ -180,9 +830,12 @@ impl Manifest {
 		manifest.bootloader = bootloader;
 		match output {
 			OutputFormat::Iso => manifest.iso = iso.or(manifest.iso),
-			OutputFormat::Device => todo!("DeviceBuilder not implemented?"),
-			OutputFormat::DiskImage => manifest.disk = disk.or(manifest.disk),
+			OutputFormat::DiskImage | OutputFormat::Device => {
+				manifest.disk = disk.or(manifest.disk)
+			},
 			OutputFormat::Folder => manifest.out_file = None,
+			OutputFormat::Tar => manifest.tar = tar.or(manifest.tar),
+			OutputFormat::Oci => manifest.oci = oci.or(manifest.oci),
 		}
 		(dnf.packages, dnf.arch_packages, dnf.arch_exclude, dnf.exclude, dnf.repodir) = (
 			manifest.dnf.packages,
@@ -199,6 +852,24 @@ impl Manifest {
 	}
 }
 
+#[test]
+fn test_expand_name() {
+	let manifest: Manifest = serde_yaml::from_str(
+		"distro: Ultramarine Linux\ndnf:\n  arch: aarch64\n  releasever: 40\n",
+	)
+	.unwrap();
+
+	assert_eq!(
+		manifest.expand_name("{distro}-{releasever}-{arch}-{date}.{ext}", "iso", "2024-01-01"),
+		"Ultramarine-Linux-40-aarch64-2024-01-01.iso"
+	);
+	assert_eq!(
+		manifest.expand_name("out/{distro}.{ext}", "", "2024-01-01"),
+		"out/Ultramarine-Linux"
+	);
+	assert_eq!(manifest.expand_name("plain.img", "img", "2024-01-01"), "plain.img");
+}
+
 #[derive(Deserialize, Debug, Clone, Serialize, Default)]
 pub struct ScriptsManifest {
 	#[serde(default)]
@@ -273,18 +944,650 @@ fn test_dev_name() {
 	assert_eq!(devname, "/dev/sda1");
 }
 
-#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq, Default)]
-pub struct PartitionLayout {
-	pub size: Option<ByteSize>,
-	pub partitions: Vec<Partition>,
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq, Default)]
+pub struct PartitionLayout {
+	/// Size of the disk image, `auto` to fit the partitions and the root filesystem
+	pub size: Option<DiskSize>,
+	/// Partitions start at multiples of this, 1 MiB if unset
+	#[serde(default)]
+	pub alignment: Option<ByteSize>,
+	/// Free space added to the measured root filesystem with `size: auto`, in percent
+	#[serde(default = "default_headroom")]
+	pub headroom: u8,
+	pub partitions: Vec<Partition>,
+	/// Format of the final disk image, the raw image is converted with `qemu-img` if needed
+	#[serde(default)]
+	pub format: DiskFormat,
+	/// Compress qcow2 images with the given algorithm, only used if `format` is `qcow2`
+	#[serde(default)]
+	pub qcow2_compression: Option<Qcow2Compression>,
+	/// Compress the finished image file, e.g. `katsu.img` becomes `katsu.img.xz`
+	#[serde(default)]
+	pub compression: Option<ImageCompression>,
+	/// Generate a `.bmap` file next to the image for `bmaptool copy`, only for raw images
+	#[serde(default)]
+	pub bmap: bool,
+	/// LVM volume groups, backed by partitions with the `lvm` filesystem
+	#[serde(default)]
+	pub lvm: Vec<VolumeGroup>,
+	/// How filesystems are referred to in fstab or mount units, unless set per partition
+	#[serde(default)]
+	pub mount_by: MountBy,
+	/// Generate systemd `.mount` and `.swap` units instead of `/etc/fstab`, for images relying on
+	/// the Discoverable Partitions Specification to find the root filesystem
+	#[serde(default)]
+	pub mount_units: bool,
+	/// Partition table to create, GPT unless the bootloader needs an MBR
+	#[serde(default)]
+	pub label: Option<DiskLabel>,
+}
+
+fn default_headroom() -> u8 {
+	25
+}
+
+/// Size of a disk image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskSize {
+	Fixed(ByteSize),
+	/// Fit the partitions and the root filesystem, measured after building it
+	Auto,
+}
+
+impl<'de> Deserialize<'de> for DiskSize {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		match SizeRepr::deserialize(deserializer)? {
+			SizeRepr::Str(s) if s == "auto" => Ok(Self::Auto),
+			SizeRepr::Str(s) => s.parse().map(Self::Fixed).map_err(serde::de::Error::custom),
+			SizeRepr::Int(b) => Ok(Self::Fixed(ByteSize::b(b))),
+		}
+	}
+}
+
+impl serde::Serialize for DiskSize {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		match self {
+			Self::Fixed(size) => serializer.serialize_u64(size.as_u64()),
+			Self::Auto => serializer.serialize_str("auto"),
+		}
+	}
+}
+
+/// Size of a partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionSize {
+	Bytes(ByteSize),
+	/// 512 byte sectors, written as e.g. `2048s`
+	Sectors(u64),
+	/// Percentage of the disk, written as e.g. `25%`
+	Percent(u8),
+}
+
+impl PartitionSize {
+	/// Size in bytes on a disk of `disk_size` bytes
+	pub fn bytes(&self, disk_size: u64) -> u64 {
+		match self {
+			Self::Bytes(size) => size.as_u64(),
+			Self::Sectors(sectors) => sectors * 512,
+			// rounded down to whole sectors
+			Self::Percent(percent) => disk_size * u64::from(*percent) / 100 / 512 * 512,
+		}
+	}
+
+	/// Size in bytes if it does not depend on the disk size
+	pub fn fixed(&self) -> Option<u64> {
+		(!matches!(self, Self::Percent(_))).then(|| self.bytes(0))
+	}
+}
+
+impl std::str::FromStr for PartitionSize {
+	type Err = color_eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		if let Some(percent) = s.strip_suffix('%') {
+			let percent = percent.trim().parse()?;
+			if !(1..=100).contains(&percent) {
+				bail!("Partition size {s} is not between 1% and 100%");
+			}
+			Ok(Self::Percent(percent))
+		} else if let Some(sectors) = s.strip_suffix('s').and_then(|n| n.trim().parse().ok()) {
+			Ok(Self::Sectors(sectors))
+		} else {
+			s.parse().map(Self::Bytes).map_err(|e| color_eyre::eyre::eyre!("{e}"))
+		}
+	}
+}
+
+/// Sizes are either a number of bytes or a string with a unit
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SizeRepr {
+	Int(u64),
+	Str(String),
+}
+
+impl<'de> Deserialize<'de> for PartitionSize {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		match SizeRepr::deserialize(deserializer)? {
+			SizeRepr::Str(s) => s.parse().map_err(serde::de::Error::custom),
+			SizeRepr::Int(b) => Ok(Self::Bytes(ByteSize::b(b))),
+		}
+	}
+}
+
+impl serde::Serialize for PartitionSize {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		match self {
+			Self::Bytes(size) => serializer.serialize_u64(size.as_u64()),
+			Self::Sectors(sectors) => serializer.serialize_str(&format!("{sectors}s")),
+			Self::Percent(percent) => serializer.serialize_str(&format!("{percent}%")),
+		}
+	}
+}
+
+#[test]
+fn test_partition_size() {
+	let sizes: Vec<PartitionSize> = serde_yaml::from_str("[512MiB, 2048s, 25%, 4096]").unwrap();
+	assert_eq!(
+		sizes,
+		[
+			PartitionSize::Bytes(ByteSize::mib(512)),
+			PartitionSize::Sectors(2048),
+			PartitionSize::Percent(25),
+			PartitionSize::Bytes(ByteSize::b(4096)),
+		]
+	);
+	assert_eq!(sizes[1].bytes(0), 1024 * 1024);
+	assert_eq!(sizes[2].bytes(8 << 30), 2 << 30);
+	assert_eq!(sizes[2].fixed(), None);
+	assert!(serde_yaml::from_str::<PartitionSize>("150%").is_err());
+
+	let size: DiskSize = serde_yaml::from_str("auto").unwrap();
+	assert_eq!(size, DiskSize::Auto);
+	let size: DiskSize = serde_yaml::from_str("8GiB").unwrap();
+	assert_eq!(size, DiskSize::Fixed(ByteSize::gib(8)));
+}
+
+/// Partition table of a disk
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskLabel {
+	#[default]
+	Gpt,
+	/// MBR partition table, at most 4 primary partitions
+	Msdos,
+	/// GPT with a hybrid MBR mirroring up to 3 partitions that set `mbr_type`,
+	/// for firmware that only reads MBR partition tables
+	Hybrid,
+}
+
+impl DiskLabel {
+	/// Name of the label for `parted mklabel`, hybrid MBRs are added to GPT afterwards
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Gpt | Self::Hybrid => "gpt",
+			Self::Msdos => "msdos",
+		}
+	}
+
+	/// Bytes taken by the partition table at the start and the end of the disk
+	fn table_sizes(self) -> (u64, u64) {
+		match self {
+			// the MBR
+			Self::Msdos => (512, 0),
+			// the (primary and backup) GPT headers and partition entries take 34 and 33 sectors
+			Self::Gpt | Self::Hybrid => (34 * 512, 33 * 512),
+		}
+	}
+
+	/// Bytes of a disk of `disk_size` bytes outside of the partition table
+	pub fn usable(self, disk_size: u64) -> std::ops::Range<u64> {
+		let (head, tail) = self.table_sizes();
+		head..disk_size.saturating_sub(tail)
+	}
+}
+
+#[test]
+fn test_disk_label_usable() {
+	const MIB: u64 = 1024 * 1024;
+	// Allwinner SPL at 8 KiB only fits behind an MBR
+	assert!(DiskLabel::Msdos.usable(64 * MIB).contains(&8192));
+	assert!(!DiskLabel::Gpt.usable(64 * MIB).contains(&8192));
+	assert!(!DiskLabel::Hybrid.usable(64 * MIB).contains(&8192));
+	// Rockchip idbloader at 32 KiB fits behind both
+	assert!(DiskLabel::Gpt.usable(64 * MIB).contains(&(32 * 1024)));
+
+	assert_eq!(DiskLabel::Msdos.usable(64 * MIB), 512..64 * MIB);
+	assert_eq!(DiskLabel::Gpt.usable(64 * MIB), 34 * 512..64 * MIB - 33 * 512);
+}
+
+/// How the booted system finds a filesystem
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MountBy {
+	/// `UUID=`, the filesystem UUID
+	#[default]
+	Uuid,
+	/// `PARTUUID=`, the GPT partition UUID, only for unencrypted partitions
+	PartUuid,
+	/// `LABEL=`, the filesystem label
+	Label,
+}
+
+/// How a partition or logical volume is mounted by the booted system
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct MountSettings {
+	/// Extra mount options, e.g. `noatime`
+	#[serde(default)]
+	pub mount_options: Vec<String>,
+	/// fsck pass number, defaults to 0 for efi, btrfs and swap and 2 for everything else.
+	/// Not used for mount units
+	#[serde(default)]
+	pub fsck_pass: Option<u8>,
+	/// Whether to add an fstab entry or mount unit for it at all
+	#[serde(default = "_default_true")]
+	pub fstab: bool,
+	/// Overrides [`PartitionLayout::mount_by`]
+	#[serde(default)]
+	pub mount_by: Option<MountBy>,
+}
+
+impl Default for MountSettings {
+	fn default() -> Self {
+		Self { mount_options: vec![], fsck_pass: None, fstab: true, mount_by: None }
+	}
+}
+
+/// An LVM volume group created on one or more partitions
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct VolumeGroup {
+	/// Name of the volume group, should not clash with volume groups on the host
+	pub name: String,
+	/// Logical volumes, created in order
+	#[serde(default)]
+	pub volumes: Vec<LogicalVolume>,
+}
+
+/// An LVM logical volume carrying a filesystem
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct LogicalVolume {
+	pub name: String,
+	/// Size of the logical volume
+	pub size: Option<ByteSize>,
+	/// Size of the logical volume in percent of the remaining free space of the volume group,
+	/// used if `size` is not specified. Takes all of the free space if neither are set
+	pub percent_free: Option<u8>,
+	/// Filesystem of the logical volume
+	pub filesystem: String,
+	/// The mountpoint of the logical volume, use `-` to not mount it
+	pub mountpoint: String,
+	#[serde(flatten)]
+	pub mkfs: MkfsOptions,
+	#[serde(flatten)]
+	pub mount: MountSettings,
+}
+
+impl LogicalVolume {
+	/// Arguments for the size of the logical volume passed to `lvcreate`
+	fn size_args(&self) -> Result<Vec<String>> {
+		Ok(match (self.size, self.percent_free) {
+			(Some(_), Some(_)) => {
+				bail!("Logical volume {} has both `size` and `percent_free` set", self.name)
+			},
+			(Some(size), None) => vec!["-L".to_string(), format!("{}b", size.as_u64())],
+			(None, Some(p @ 1..=100)) => vec!["-l".to_string(), format!("{p}%FREE")],
+			(None, Some(p)) => {
+				bail!("Invalid `percent_free` for logical volume {}: {p}", self.name)
+			},
+			(None, None) => vec!["-l".to_string(), "100%FREE".to_string()],
+		})
+	}
+}
+
+/// Virtual disk image formats, see `qemu-img(1)`
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiskFormat {
+	/// Raw sparse disk image
+	#[default]
+	Raw,
+	/// QEMU/libvirt
+	Qcow2,
+	/// VMware
+	Vmdk,
+	/// Hyper-V
+	Vhdx,
+	/// VirtualBox
+	Vdi,
+}
+
+impl DiskFormat {
+	/// File extension for this format
+	pub fn ext(&self) -> &'static str {
+		match self {
+			Self::Raw => "img",
+			Self::Qcow2 => "qcow2",
+			Self::Vmdk => "vmdk",
+			Self::Vhdx => "vhdx",
+			Self::Vdi => "vdi",
+		}
+	}
+
+	/// Format name as understood by `qemu-img -O`
+	pub fn qemu_name(&self) -> &'static str {
+		match self {
+			Self::Raw => "raw",
+			Self::Qcow2 => "qcow2",
+			Self::Vmdk => "vmdk",
+			Self::Vhdx => "vhdx",
+			Self::Vdi => "vdi",
+		}
+	}
+}
+
+/// Compression algorithms for finished image files
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageCompression {
+	Xz,
+	Zstd,
+}
+
+impl ImageCompression {
+	/// File extension appended to the compressed image
+	pub fn ext(&self) -> &'static str {
+		match self {
+			Self::Xz => "xz",
+			Self::Zstd => "zst",
+		}
+	}
+}
+
+/// Compression algorithms supported by qcow2 images
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Qcow2Compression {
+	Zlib,
+	Zstd,
+}
+
+impl Qcow2Compression {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Zlib => "zlib",
+			Self::Zstd => "zstd",
+		}
+	}
+}
+
+#[derive(Serialize, Debug)]
+struct TplFstabEntry<'a> {
+	source: String,
+	mp: &'a str,
+	fsname: &'a str,
+	options: String,
+	fsck: u8,
+}
+
+#[derive(Serialize, Debug)]
+struct TplCrypttabEntry {
+	name: String,
+	uuid: String,
+	options: String,
+}
+
+/// Name of the device mapper device encrypted partition `index` is opened as during the build
+fn luks_mapper(index: usize) -> String {
+	format!("katsu-luks{index}")
+}
+
+/// The block device a [`MountEntry`] is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountSource<'a> {
+	/// Index of the partition, starting from 1
+	Partition(usize),
+	/// Logical volume `lv` in volume group `vg`
+	LogicalVolume { vg: &'a str, lv: &'a str },
+}
+
+/// A filesystem mounted from a [`PartitionLayout`]: a whole partition, a btrfs subvolume
+/// or an LVM logical volume
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountEntry<'a> {
+	pub source: MountSource<'a>,
+	pub filesystem: &'a str,
+	pub mountpoint: &'a str,
+	/// Mount options needed to build the image, e.g. `subvol=`
+	pub options: Vec<String>,
+	pub settings: &'a MountSettings,
+}
+
+impl<'a> MountEntry<'a> {
+	/// Filesystem type as understood by `mount`
+	fn fstype(&self) -> &'a str {
+		if self.filesystem == "efi" {
+			"vfat"
+		} else {
+			self.filesystem
+		}
+	}
+
+	/// Mount options for the booted system, `defaults` if there are none
+	fn fstab_options(&self) -> String {
+		let options =
+			self.options.iter().chain(&self.settings.mount_options).cloned().collect::<Vec<_>>();
+		if options.is_empty() {
+			"defaults".to_string()
+		} else {
+			options.join(",")
+		}
+	}
+
+	fn fsck_pass(&self) -> u8 {
+		self.settings.fsck_pass.unwrap_or(match self.filesystem {
+			"efi" | "btrfs" | "swap" => 0,
+			_ => 2,
+		})
+	}
+}
+
+/// Escapes a path for use as a systemd unit name, like `systemd-escape --path`
+pub fn systemd_escape_path(path: &str) -> String {
+	let path = path.split('/').filter(|c| !c.is_empty()).collect::<Vec<_>>().join("/");
+	if path.is_empty() {
+		return "-".to_string();
+	}
+	path.bytes()
+		.enumerate()
+		.map(|(i, b)| match b {
+			b'/' => "-".to_string(),
+			b'.' if i > 0 => ".".to_string(),
+			b if b.is_ascii_alphanumeric() || b == b':' || b == b'_' => (b as char).to_string(),
+			b => format!("\\x{b:02x}"),
+		})
+		.collect()
+}
+
+#[test]
+fn test_systemd_escape_path() {
+	assert_eq!(systemd_escape_path("/"), "-");
+	assert_eq!(systemd_escape_path("/boot/efi/"), "boot-efi");
+	assert_eq!(systemd_escape_path("/var/lib/.hidden"), "var-lib-.hidden");
+	assert_eq!(systemd_escape_path("/dev/disk/by-uuid/ab-12"), "dev-disk-by\\x2duuid-ab\\x2d12");
+}
+
+/// Size of the dm-verity hash tree of `data` bytes with 4 KiB blocks and sha256, rounded up to MiB
+fn verity_hash_size(data: u64) -> u64 {
+	const BLOCK: u64 = 4096;
+	// every hash block holds 128 sha256 hashes of the level below
+	let mut blocks = data.div_ceil(BLOCK);
+	// the superblock
+	let mut total = 1;
+	while blocks > 1 {
+		blocks = blocks.div_ceil(BLOCK / 32);
+		total += blocks;
+	}
+	(total * BLOCK).div_ceil(1024 * 1024) * 1024 * 1024
+}
+
+/// Adds a hybrid MBR to the GPT on `disk`, with the `(partition number, MBR type, bootable)`
+/// entries first and the protective partition last
+fn write_hybrid_mbr(disk: &Path, entries: &[(usize, u8, bool)]) -> Result<()> {
+	use std::io::{Seek, SeekFrom};
+
+	let parts = entries.iter().map(|(nr, ..)| nr.to_string()).collect::<Vec<_>>().join(":");
+	info!(parts, "Writing hybrid MBR");
+	cmd_lib::run_cmd!(sgdisk --hybrid=$parts $disk 2>&1)?;
+
+	// sgdisk guesses the types from the GPT ones and has no option for the boot flag
+	let mut mbr = fs::OpenOptions::new().write(true).open(disk)?;
+	for (k, (_, mbr_type, bootable)) in entries.iter().enumerate() {
+		let entry = 446 + 16 * k as u64;
+		mbr.seek(SeekFrom::Start(entry))?;
+		mbr.write_all(&[if *bootable { 0x80 } else { 0 }])?;
+		mbr.seek(SeekFrom::Start(entry + 4))?;
+		mbr.write_all(&[*mbr_type])?;
+	}
+	mbr.sync_all()?;
+	Ok(())
+}
+
+/// Formats `devname` with the filesystem `fsname`
+fn mkfs(fsname: &str, devname: &str, options: &MkfsOptions) -> Result<()> {
+	if fsname == "none" {
+		return Ok(());
+	}
+	let (cmd, args) = options.command(fsname)?;
+	debug!(fsname, devname, "Formatting");
+	trace!("{cmd} {args:?} {devname}");
+	cmd_lib::run_cmd!($cmd $[args] $devname 2>&1)?;
+	Ok(())
+}
+
+/// Filesystem creation options for partitions and logical volumes
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq, Default)]
+pub struct MkfsOptions {
+	/// Filesystem label, e.g. for bootloaders or `mount_by: label` to find the filesystem
+	#[serde(default)]
+	pub fs_label: Option<String>,
+	/// Fixed filesystem UUID for reproducible images, a volume ID like `ABCD-1234` for FAT
+	#[serde(default)]
+	pub fs_uuid: Option<String>,
+	/// Extra arguments passed to mkfs as-is, e.g. `[-O, ^metadata_csum]` for ext4
+	#[serde(default)]
+	pub mkfs_args: Vec<String>,
+}
+
+impl MkfsOptions {
+	/// The mkfs command for `fsname` and its arguments, without the device
+	fn command(&self, fsname: &str) -> Result<(String, Vec<String>)> {
+		let is_fat = matches!(fsname, "efi" | "vfat" | "fat");
+		let (cmd, mut args) = match fsname {
+			// Some stupid hackery checks for the args of mkfs.fat
+			"efi" => ("mkfs.fat".to_string(), vec!["-F32".to_string()]),
+			"swap" => ("mkswap".to_string(), vec![]),
+			fs => (format!("mkfs.{fs}"), vec![]),
+		};
+
+		// flag for the label, longest label allowed, flag for the UUID
+		let (label_flag, max_label, uuid_flag) = match fsname {
+			"ext2" | "ext3" | "ext4" | "swap" => ("-L", 16, "-U"),
+			"xfs" => ("-L", 12, "-m"),
+			"btrfs" => ("-L", 255, "-U"),
+			"f2fs" => ("-l", 512, "-U"),
+			_ if is_fat => ("-n", 11, "-i"),
+			_ if self.fs_label.is_none() && self.fs_uuid.is_none() => ("", 0, ""),
+			_ => bail!(
+				"`fs_label` and `fs_uuid` are not supported for {fsname}, use `mkfs_args` instead"
+			),
+		};
+
+		if let Some(label) = &self.fs_label {
+			if label.len() > max_label {
+				bail!("Filesystem label `{label}` is longer than {max_label} bytes, the maximum for {fsname}");
+			}
+			args.extend([label_flag.to_string(), label.clone()]);
+		}
+
+		if let Some(uuid) = &self.fs_uuid {
+			let uuid = if is_fat {
+				let volid = uuid.replace('-', "");
+				if volid.len() != 8 || !volid.chars().all(|c| c.is_ascii_hexdigit()) {
+					bail!("FAT volume IDs are 8 hex digits like `ABCD-1234`, got `{uuid}`");
+				}
+				volid
+			} else if fsname == "xfs" {
+				format!("uuid={uuid}")
+			} else {
+				uuid.clone()
+			};
+			args.extend([uuid_flag.to_string(), uuid]);
+		}
+
+		args.extend(self.mkfs_args.iter().cloned());
+		Ok((cmd, args))
+	}
+}
+
+#[test]
+fn test_mkfs_command() {
+	let options = |label: &str, uuid: &str| MkfsOptions {
+		fs_label: Some(label.to_string()),
+		fs_uuid: Some(uuid.to_string()),
+		mkfs_args: vec![],
+	};
+	let uuid = "2f3a9c1e-6b1d-4a57-9a7e-3c0e8f1b2d4a";
+
+	let cases = [
+		("ext4", "root", uuid, "mkfs.ext4 -L root -U 2f3a9c1e-6b1d-4a57-9a7e-3c0e8f1b2d4a"),
+		("xfs", "root", uuid, "mkfs.xfs -L root -m uuid=2f3a9c1e-6b1d-4a57-9a7e-3c0e8f1b2d4a"),
+		("btrfs", "root", uuid, "mkfs.btrfs -L root -U 2f3a9c1e-6b1d-4a57-9a7e-3c0e8f1b2d4a"),
+		("f2fs", "root", uuid, "mkfs.f2fs -l root -U 2f3a9c1e-6b1d-4a57-9a7e-3c0e8f1b2d4a"),
+		("efi", "EFI", "ABCD-1234", "mkfs.fat -F32 -n EFI -i ABCD1234"),
+		("swap", "swap", uuid, "mkswap -L swap -U 2f3a9c1e-6b1d-4a57-9a7e-3c0e8f1b2d4a"),
+	];
+	for (fsname, label, uuid, expected) in cases {
+		let (cmd, args) = options(label, uuid).command(fsname).unwrap();
+		assert_eq!(format!("{cmd} {}", args.join(" ")), expected);
+	}
+
+	assert!(options("EFI", "not-a-volid").command("efi").is_err());
+	assert!(options("a-label-too-long", uuid).command("xfs").is_err());
+	assert!(options("root", uuid).command("ntfs").is_err());
+
+	let args =
+		MkfsOptions { mkfs_args: vec!["-O".into(), "^metadata_csum".into()], ..Default::default() };
+	assert_eq!(args.command("ext4").unwrap().1, ["-O", "^metadata_csum"]);
+}
+
+/// Whether the partition/subvolume should be mounted at `mountpoint`
+fn is_mountpoint(mountpoint: &str) -> bool {
+	mountpoint.starts_with('/')
 }
 
-#[derive(Serialize, Debug)]
-struct TplFstabEntry<'a> {
-	uuid: String,
-	mp: String,
-	fsname: &'a str,
-	fsck: u8,
+/// Orders mountpoints from the least nested to the most nested, so parents are mounted first
+fn mountpoint_order(a: &str, b: &str) -> std::cmp::Ordering {
+	// trim trailing slashes
+	let am = a.trim_end_matches('/').matches('/').count();
+	let bm = b.trim_end_matches('/').matches('/').count();
+	if a.is_empty() {
+		// empty mountpoint should always come first
+		std::cmp::Ordering::Less
+	} else if b.is_empty() {
+		// empty mountpoint should always come first
+		std::cmp::Ordering::Greater
+	} else if a == "/" {
+		// / should always come first
+		std::cmp::Ordering::Less
+	} else if b == "/" {
+		// / should always come first
+		std::cmp::Ordering::Greater
+	} else if am == bm {
+		// alphabetical order
+		a.cmp(b)
+	} else {
+		am.cmp(&bm)
+	}
 }
 
 #[allow(dead_code)]
@@ -307,6 +1610,13 @@ impl PartitionLayout {
 		self.partitions.iter().find(|p| p.mountpoint == mountpoint)
 	}
 
+	/// Get the EFI system partition, if the layout has one
+	pub fn esp(&self) -> Option<&Partition> {
+		self.partitions
+			.iter()
+			.find(|p| p.partition_type == PartitionType::Esp || p.filesystem == "efi")
+	}
+
 	pub fn sort_partitions(&self) -> Vec<(usize, Partition)> {
 		// We should sort partitions by mountpoint, so that we can mount them in order
 		// In this case, from the least nested to the most nested, so count the number of slashes
@@ -333,200 +1643,662 @@ impl PartitionLayout {
 
 		let mut ordered = ordered.into_iter().collect::<Vec<_>>();
 
-		ordered.sort_unstable_by(|(_, a), (_, b)| {
-			// trim trailing slashes
-			let am = a.mountpoint.trim_end_matches('/').matches('/').count();
-			let bm = b.mountpoint.trim_end_matches('/').matches('/').count();
-			if a.mountpoint.is_empty() {
-				// empty mountpoint should always come first
-				std::cmp::Ordering::Less
-			} else if b.mountpoint.is_empty() {
-				// empty mountpoint should always come first
-				std::cmp::Ordering::Greater
-			} else if a.mountpoint == "/" {
-				// / should always come first
-				std::cmp::Ordering::Less
-			} else if b.mountpoint == "/" {
-				// / should always come first
-				std::cmp::Ordering::Greater
-			} else if am == bm {
-				// alphabetical order
-				a.mountpoint.cmp(&b.mountpoint)
-			} else {
-				am.cmp(&bm)
-			}
-		});
+		ordered.sort_unstable_by(|(_, a), (_, b)| mountpoint_order(&a.mountpoint, &b.mountpoint));
 		ordered
 	}
 
-	pub fn mount_to_chroot(&self, disk: &Path, chroot: &Path) -> Result<()> {
-		// mount partitions to chroot
-
-		// sort partitions by mountpoint
-		let ordered: Vec<_> = self.sort_partitions();
+	/// Everything that should be mounted from this layout, whole partitions and
+	/// btrfs subvolumes alike, sorted from the least to the most nested mountpoint
+	pub fn mount_entries(&self) -> Vec<MountEntry<'_>> {
+		let mut entries = vec![];
 
-		// Ok, so for some reason the partitions are swapped?
-		for (index, part) in &ordered {
-			// println!("Partition {index}: {part:#?}");
+		for (i, part) in self.partitions.iter().enumerate() {
+			let source = MountSource::Partition(i + 1);
+			if ["none", "swap", "lvm"].contains(&part.filesystem.as_str()) {
+				continue;
+			}
 
-			if part.mountpoint.is_empty()
-				|| part.filesystem == "none"
-				|| part.filesystem == "swap"
-				|| part.mountpoint == "-"
+			let mut options = vec![];
+			if let Some(compression) =
+				part.compression.as_ref().filter(|_| part.filesystem == "btrfs")
 			{
-				// skip empty mountpoints
+				options.push(format!("compress={compression}"));
+			}
+
+			if is_mountpoint(&part.mountpoint) {
+				entries.push(MountEntry {
+					source,
+					filesystem: &part.filesystem,
+					mountpoint: &part.mountpoint,
+					options: options.clone(),
+					settings: &part.mount,
+				});
+			} else if part.subvolumes.is_empty() {
 				warn!(?part, "This partition is not supposed to be mounted! Skipping... If you want this partition to be mounted, please specify a mountpoint starting with /");
+			}
+
+			if part.filesystem != "btrfs" {
 				continue;
 			}
-			let devname = partition_name(&disk.to_string_lossy(), *index);
+
+			for subvol in part.subvolumes.iter().filter(|sv| is_mountpoint(&sv.mountpoint)) {
+				let mut options = options.clone();
+				options.insert(0, format!("subvol={}", subvol.name));
+				entries.push(MountEntry {
+					source,
+					filesystem: &part.filesystem,
+					mountpoint: &subvol.mountpoint,
+					options,
+					settings: &part.mount,
+				});
+			}
+		}
+
+		for vg in &self.lvm {
+			for lv in vg.volumes.iter().filter(|lv| is_mountpoint(&lv.mountpoint)) {
+				if ["none", "swap"].contains(&lv.filesystem.as_str()) {
+					continue;
+				}
+				entries.push(MountEntry {
+					source: MountSource::LogicalVolume { vg: &vg.name, lv: &lv.name },
+					filesystem: &lv.filesystem,
+					mountpoint: &lv.mountpoint,
+					options: vec![],
+					settings: &lv.mount,
+				});
+			}
+		}
+
+		entries.sort_by(|a, b| mountpoint_order(a.mountpoint, b.mountpoint));
+		entries
+	}
+
+	pub fn mount_to_chroot(&self, disk: &Path, chroot: &Path) -> Result<()> {
+		// mount partitions and subvolumes to chroot, sorted by mountpoint
+		for entry in self.mount_entries() {
+			let devname = self.source_device(disk, entry.source);
 
 			// clean the mountpoint so we don't have the slash at the start
-			let mp_cleaned = part.mountpoint.trim_start_matches('/');
+			let mp_cleaned = entry.mountpoint.trim_start_matches('/');
 			let mountpoint = chroot.join(mp_cleaned);
 
 			std::fs::create_dir_all(&mountpoint)?;
 
-			trace!("mount {devname} {mountpoint:?}");
+			let args = if entry.options.is_empty() {
+				vec![]
+			} else {
+				vec!["-o".to_string(), entry.options.join(",")]
+			};
 
-			cmd_lib::run_cmd!(mount $devname $mountpoint 2>&1)?;
+			trace!("mount {args:?} {devname} {mountpoint:?}");
+
+			cmd_lib::run_cmd!(mount $[args] $devname $mountpoint 2>&1)?;
 		}
 
 		Ok(())
 	}
 
 	pub fn unmount_from_chroot(&self, chroot: &Path) -> Result<()> {
-		// unmount partitions from chroot
-		// sort partitions by mountpoint
-		for mp in self.sort_partitions().into_iter().rev().map(|(_, p)| p.mountpoint) {
-			if mp.is_empty() || mp == "-" {
-				continue;
-			}
-			let mp = chroot.join(mp.trim_start_matches('/'));
+		// unmount partitions from chroot, most nested first
+		for entry in self.mount_entries().into_iter().rev() {
+			let mp = chroot.join(entry.mountpoint.trim_start_matches('/'));
 			trace!("umount {mp:?}");
 			cmd_lib::run_cmd!(umount $mp 2>&1)?;
 		}
+
+		// deactivate volume groups so their physical volumes can be closed and detached
+		for vg in &self.lvm {
+			let name = &vg.name;
+			trace!("vgchange -an {name}");
+			cmd_lib::run_cmd!(vgchange -an $name 2>&1)?;
+		}
+
+		for (i, _) in self.partitions.iter().enumerate().filter(|(_, p)| p.encryption.is_some()) {
+			let mapper = luks_mapper(i + 1);
+			trace!("cryptsetup close {mapper}");
+			cmd_lib::run_cmd!(cryptsetup close $mapper 2>&1)?;
+		}
 		Ok(())
 	}
 
-	/// Generate fstab entries for the partitions
-	pub fn fstab(&self, chroot: &Path) -> Result<String> {
-		// sort partitions by mountpoint
-		let ordered = self.sort_partitions();
+	/// Device node holding the filesystem of the partition at `index` (starting from 1),
+	/// which is the opened LUKS device for encrypted partitions
+	pub fn fs_device(&self, disk: &Path, index: usize) -> String {
+		match self.partitions.get(index - 1).and_then(|p| p.encryption.as_ref()) {
+			Some(_) => format!("/dev/mapper/{}", luks_mapper(index)),
+			None => partition_name(&disk.to_string_lossy(), index),
+		}
+	}
 
-		crate::prepend_comment!(PREPEND: "/etc/fstab", "static file system information.", katsu::config::PartitionLayout::fstab);
+	/// UUIDs of the LUKS headers of all encrypted partitions, which have to be opened
+	fn luks_uuids(&self) -> Result<Vec<(usize, &Encryption, String)>> {
+		let mut uuids = vec![];
+		for (i, part) in self.partitions.iter().enumerate() {
+			let Some(encryption) = &part.encryption else { continue };
+			let mapper = luks_mapper(i + 1);
+			let status = cmd_lib::run_fun!(cryptsetup status $mapper)?;
+			bail_let!(
+				Some(dev) = status.lines().find_map(|l| l.trim().strip_prefix("device:"))
+					=> "Cannot find the backing device of {mapper}"
+			);
+			let dev = dev.trim();
+			uuids.push((i + 1, encryption, cmd_lib::run_fun!(cryptsetup luksUUID $dev)?));
+		}
+		Ok(uuids)
+	}
 
-		let mut entries = vec![];
+	/// Adds a dm-verity hash partition after every partition with `verity` enabled
+	pub fn add_verity_partitions(&mut self) -> Result<()> {
+		let mut i = 0;
+		while i < self.partitions.len() {
+			let part = &mut self.partitions[i];
+			i += 1;
+			if !part.verity {
+				continue;
+			}
 
-		ordered.iter().try_for_each(|(_, part)| -> Result<()> {
-			if part.filesystem != "none" {
-				let mp = PathBuf::from(&part.mountpoint).to_string_lossy().to_string();
-				let mountpoint_chroot = part.mountpoint.trim_start_matches('/');
-				let mountpoint_chroot = chroot.join(mountpoint_chroot);
-				let devname = cmd_lib::run_fun!(findmnt -n -o SOURCE $mountpoint_chroot)?;
+			let partition_type = match part.mountpoint.as_str() {
+				"/" => PartitionType::RootVerity,
+				"/usr" => PartitionType::UsrVerity,
+				mp => bail!("dm-verity is only supported for / and /usr, not {mp}"),
+			};
+			bail_let!(Some(size) = part.size.and_then(|s| s.fixed()) => "dm-verity protected partitions need a fixed size");
+			if part.encryption.is_some() || part.filesystem == "lvm" {
+				bail!("dm-verity is not supported for encrypted or LVM partitions");
+			}
 
-				// We will generate by UUID
-				let uuid = cmd_lib::run_fun!(blkid -s UUID -o value $devname)?;
+			// the filesystem can never be written to again
+			part.flags.get_or_insert_with(Vec::new).push(PartitionFlag::ReadOnly);
+			if !part.mount.mount_options.iter().any(|o| o == "ro") {
+				part.mount.mount_options.push("ro".to_string());
+			}
 
-				let fsname = if part.filesystem == "efi" { "vfat" } else { &part.filesystem };
-				let fsck = if part.filesystem == "efi" { 0 } else { 2 };
+			let hash = Partition {
+				label: part.label.as_ref().map(|l| format!("{l}-verity")),
+				partition_type,
+				flags: Some(vec![PartitionFlag::ReadOnly]),
+				size: Some(PartitionSize::Bytes(ByteSize::b(verity_hash_size(size)))),
+				filesystem: "none".to_string(),
+				mountpoint: "-".to_string(),
+				subvolumes: vec![],
+				default_subvolume: None,
+				compression: None,
+				encryption: None,
+				volume_group: None,
+				verity: false,
+				mkfs: MkfsOptions::default(),
+				mbr_type: None,
+				bootable: None,
+				mount: MountSettings { fstab: false, ..Default::default() },
+			};
+			self.partitions.insert(i, hash);
+			i += 1;
+		}
+		Ok(())
+	}
 
-				entries.push(TplFstabEntry { uuid, mp, fsname, fsck });
+	/// Makes the dm-verity protected partitions read-only and formats their hash partitions,
+	/// returning the kernel command line arguments to verify them at boot
+	pub fn format_verity(&self, disk: &Path, chroot: &Path) -> Result<Vec<String>> {
+		let disk_name = disk.to_string_lossy();
+		let partuuid = |dev: &str| cmd_lib::run_fun!(blkid -s PARTUUID -o value $dev);
+		let mut cmdline = vec![];
+
+		for (i, part) in self.partitions.iter().enumerate().filter(|(_, p)| p.verity) {
+			// the hash partition always comes right after its data partition
+			let (data, hash) =
+				(partition_name(&disk_name, i + 1), partition_name(&disk_name, i + 2));
+			let kind = if part.mountpoint == "/" { "root" } else { "usr" };
+
+			let mp = chroot.join(part.mountpoint.trim_start_matches('/'));
+			trace!("mount -o remount,ro {mp:?}");
+			cmd_lib::run_cmd!(mount -o remount,ro $mp 2>&1)?;
+
+			info!(data, hash, "Formatting dm-verity hash partition");
+			let out = cmd_lib::run_fun!(veritysetup format $data $hash)?;
+			bail_let!(
+				Some(roothash) = out.lines().find_map(|l| l.strip_prefix("Root hash:"))
+					=> "Cannot find the root hash of {data} in the output of veritysetup"
+			);
+			let roothash = roothash.trim();
+			info!(kind, roothash, "dm-verity root hash");
+
+			cmdline.push(format!("{kind}hash={roothash}"));
+			cmdline.push(format!("systemd.verity_{kind}_data=PARTUUID={}", partuuid(&data)?));
+			cmdline.push(format!("systemd.verity_{kind}_hash=PARTUUID={}", partuuid(&hash)?));
+			if kind == "usr" {
+				cmdline.push("mount.usr=/dev/mapper/usr".to_string());
+				cmdline.push("mount.usrflags=ro".to_string());
 			}
-			Ok(())
-		})?;
+		}
+		Ok(cmdline)
+	}
+
+	/// Kernel command line arguments needed to unlock encrypted partitions and
+	/// activate volume groups at boot
+	pub fn boot_cmdline(&self) -> Result<Vec<String>> {
+		let luks = self.luks_uuids()?.into_iter().map(|(.., uuid)| format!("rd.luks.uuid={uuid}"));
+		let lvm = self.lvm.iter().map(|vg| format!("rd.lvm.vg={}", vg.name));
+		Ok(luks.chain(lvm).collect())
+	}
+
+	/// Generate crypttab entries for the encrypted partitions, `None` if there are none
+	pub fn crypttab(&self) -> Result<Option<String>> {
+		crate::prepend_comment!(PREPEND: "/etc/crypttab", "encrypted block devices.", katsu::config::PartitionLayout::crypttab);
+
+		let entries = self
+			.luks_uuids()?
+			.into_iter()
+			.map(|(_, encryption, uuid)| {
+				let mut options = vec!["luks", "discard"];
+				if encryption.tpm2 {
+					options.push("tpm2-device=auto");
+				}
+				if encryption.fido2 {
+					options.push("fido2-device=auto");
+				}
+				if encryption.tpm2 || encryption.fido2 {
+					info!(uuid, "Remember to enroll the TPM2/FIDO2 token with systemd-cryptenroll on first boot");
+				}
+				TplCrypttabEntry {
+					name: encryption.name.clone().unwrap_or_else(|| format!("luks-{uuid}")),
+					uuid,
+					options: options.join(","),
+				}
+			})
+			.collect::<Vec<_>>();
+
+		if entries.is_empty() {
+			return Ok(None);
+		}
+
+		trace!(?entries, "crypttab entries generated");
+
+		Ok(Some(crate::tpl!("crypttab.tera" => { PREPEND, entries })))
+	}
+
+	/// Swap partitions and logical volumes, which are not mounted to the chroot
+	fn swap_entries(&self) -> Vec<MountEntry<'_>> {
+		let parts = self.partitions.iter().enumerate().filter(|(_, p)| p.filesystem == "swap").map(
+			|(i, p)| MountEntry {
+				source: MountSource::Partition(i + 1),
+				filesystem: "swap",
+				mountpoint: "none",
+				options: vec![],
+				settings: &p.mount,
+			},
+		);
+		let lvs = self.lvm.iter().flat_map(|vg| {
+			vg.volumes.iter().filter(|lv| lv.filesystem == "swap").map(|lv| MountEntry {
+				source: MountSource::LogicalVolume { vg: &vg.name, lv: &lv.name },
+				filesystem: "swap",
+				mountpoint: "none",
+				options: vec![],
+				settings: &lv.mount,
+			})
+		});
+		parts.chain(lvs).collect()
+	}
+
+	/// Everything the booted system should mount, including swap
+	fn fstab_entries(&self) -> Vec<MountEntry<'_>> {
+		let mut entries = self.mount_entries();
+		entries.extend(self.swap_entries());
+		entries.retain(|e| e.settings.fstab);
+		entries
+	}
+
+	/// Device node of the filesystem on `source`
+	fn source_device(&self, disk: &Path, source: MountSource) -> String {
+		match source {
+			MountSource::Partition(index) => self.fs_device(disk, index),
+			MountSource::LogicalVolume { vg, lv } => format!("/dev/{vg}/{lv}"),
+		}
+	}
+
+	/// The disk this layout is mounted from in `chroot`
+	fn mounted_disk(&self, chroot: &Path) -> Result<PathBuf> {
+		bail_let!(Some(entry) = self.mount_entries().into_iter().next() => "Nothing from the disk layout is mounted");
+		let mp = chroot.join(entry.mountpoint.trim_start_matches('/'));
+		let source = cmd_lib::run_fun!(findmnt -n --nofsroot -o SOURCE $mp)?;
+		// lists the device and everything below it, e.g. logical volume, LUKS device, partition, disk
+		let parents = cmd_lib::run_fun!(lsblk -nrsp -o NAME,TYPE $source)?;
+		bail_let!(
+			Some((disk, _)) = parents.lines().filter_map(|l| l.split_once(' ')).find(|(_, t)| ["disk", "loop"].contains(t))
+				=> "Cannot find the disk {source} is on"
+		);
+		Ok(PathBuf::from(disk))
+	}
+
+	/// How the booted system finds the filesystem of `entry`, as a tag and value like `("UUID", "...")`
+	fn mount_spec(&self, disk: &Path, entry: &MountEntry) -> Result<(&'static str, String)> {
+		let (tag, device) = match entry.settings.mount_by.unwrap_or(self.mount_by) {
+			MountBy::Uuid => ("UUID", self.source_device(disk, entry.source)),
+			MountBy::Label => ("LABEL", self.source_device(disk, entry.source)),
+			MountBy::PartUuid => match entry.source {
+				MountSource::Partition(index)
+					if self.partitions[index - 1].encryption.is_none() =>
+				{
+					("PARTUUID", partition_name(&disk.to_string_lossy(), index))
+				},
+				_ => bail!(
+					"{} is not on a plain partition and cannot be mounted by PARTUUID",
+					entry.mountpoint
+				),
+			},
+		};
+		let value = cmd_lib::run_fun!(blkid -s $tag -o value $device)?;
+		if value.is_empty() {
+			bail!("{device} has no {tag} to mount {} by", entry.mountpoint);
+		}
+		Ok((tag, value))
+	}
+
+	/// Generate fstab entries for the partitions
+	pub fn fstab(&self, chroot: &Path) -> Result<String> {
+		crate::prepend_comment!(PREPEND: "/etc/fstab", "static file system information.", katsu::config::PartitionLayout::fstab);
+
+		let disk = self.mounted_disk(chroot)?;
+
+		let entries = (self.fstab_entries().iter())
+			.map(|entry| {
+				let (tag, value) = self.mount_spec(&disk, entry)?;
+				Ok(TplFstabEntry {
+					source: format!("{tag}={value}"),
+					mp: entry.mountpoint,
+					fsname: entry.fstype(),
+					options: entry.fstab_options(),
+					fsck: entry.fsck_pass(),
+				})
+			})
+			.collect::<Result<Vec<_>>>()?;
 
 		trace!(?entries, "fstab entries generated");
 
 		Ok(crate::tpl!("fstab.tera" => { PREPEND, entries }))
 	}
 
-	pub fn apply(&self, disk: &PathBuf, target_arch: &str) -> Result<()> {
+	/// Generate systemd mount and swap units for the partitions instead of fstab entries,
+	/// as `(unit name, contents)`
+	pub fn mount_units(&self, chroot: &Path) -> Result<Vec<(String, String)>> {
+		crate::prepend_comment!(PREPEND: "/etc/systemd/system", "mount units for the disk layout.", katsu::config::PartitionLayout::mount_units);
+
+		let disk = self.mounted_disk(chroot)?;
+		let mut units = vec![];
+
+		for entry in self.fstab_entries() {
+			// the root filesystem is mounted by the initrd
+			if entry.mountpoint == "/" {
+				continue;
+			}
+			let (tag, value) = self.mount_spec(&disk, &entry)?;
+			let what = format!("/dev/disk/by-{}/{value}", tag.to_lowercase());
+
+			if entry.filesystem == "swap" {
+				let name = format!("{}.swap", systemd_escape_path(&what));
+				units.push((name, crate::tpl!("swap.tera" => { PREPEND, what })));
+			} else {
+				let name = format!("{}.mount", systemd_escape_path(entry.mountpoint));
+				let unit = crate::tpl!("mount.tera" => {
+					PREPEND,
+					what,
+					mountpoint: entry.mountpoint,
+					fstype: entry.fstype(),
+					options: entry.fstab_options()
+				});
+				units.push((name, unit));
+			}
+		}
+
+		trace!(?units, "mount units generated");
+		Ok(units)
+	}
+
+	pub fn apply(
+		&self, disk: &PathBuf, target_arch: &str, label: DiskLabel, wipe: WipePolicy,
+	) -> Result<()> {
+		self.validate_lvm()?;
+		if label == DiskLabel::Msdos && self.partitions.len() > 4 {
+			bail!(
+				"MBR partition tables hold at most 4 partitions, {} given",
+				self.partitions.len()
+			);
+		}
+		let hybrid =
+			if label == DiskLabel::Hybrid { self.hybrid_mbr_partitions()? } else { vec![] };
+
 		// This is a destructive operation, so we need to make sure we don't accidentally wipe the wrong disk
+		crate::util::ensure_safe_to_wipe(disk, wipe)?;
 
 		info!("Applying partition layout to disk: {disk:#?}");
 
-		// format disk with GPT
+		let disk_size = cmd_lib::run_fun!(blockdev --getsize64 $disk)?.trim().parse()?;
+		let bounds = self.partition_bounds(disk_size, label)?;
 
-		trace!("Formatting disk with GPT");
-		trace!("parted -s {disk:?} mklabel gpt");
-		cmd_lib::run_cmd!(parted -s $disk mklabel gpt 2>&1)?;
+		let label_name = label.as_str();
+		trace!("parted -s {disk:?} mklabel {label_name}");
+		cmd_lib::run_cmd!(parted -s $disk mklabel $label_name 2>&1)?;
 
 		// create partitions
-		self.partitions.iter().try_fold((1, 0), |(i, mut last_end), part| {
-			let devname = partition_name(&disk.to_string_lossy(), i);
-			trace!(devname, "Creating partition {i}: {part:#?}");
-
-			let span = tracing::trace_span!("partition", devname);
-			let _enter = span.enter();
-
-			let start_string = if i == 1 {
-				// create partition at start of disk
-				"0".to_string()
-			} else {
-				// create partition after last partition
-				format!("{}MiB", last_end / 1024 / 1024)
-			};
+		self.partitions.iter().zip(bounds).enumerate().try_for_each(
+			|(i, (part, (start, end)))| {
+				let i = i + 1;
+				let devname = partition_name(&disk.to_string_lossy(), i);
+				trace!(devname, "Creating partition {i}: {part:#?}");
+
+				let span = tracing::trace_span!("partition", devname);
+				let _enter = span.enter();
+
+				// in sectors, the end is inclusive. Already aligned, so parted should not move them
+				let start_string = format!("{}s", start / 512);
+				let end_string = format!("{}s", end / 512 - 1);
+
+				debug!(start = start_string, end = end_string, "Creating partition");
+				trace!(
+					"parted -s -a none {disk:?} mkpart primary fat32 {start_string} {end_string}"
+				);
+				cmd_lib::run_cmd!(parted -s -a none $disk mkpart primary fat32 $start_string $end_string 2>&1)?;
+
+				if label != DiskLabel::Msdos {
+					let part_type_uuid = part.partition_type.uuid(target_arch)?;
+
+					debug!("Setting partition type");
+					trace!("parted -s {disk:?} type {i} {part_type_uuid}");
+					cmd_lib::run_cmd!(parted -s $disk type $i $part_type_uuid 2>&1)?;
+
+					if let Some(flags) = &part.flags {
+						debug!("Setting partition attribute flags");
+
+						for flag in flags {
+							let position = flag.flag_position();
+							trace!("sgdisk -A {i}:set:{position} {disk:?}");
+							cmd_lib::run_cmd!(sgdisk -A $i:set:$position $disk 2>&1)?;
+						}
+					}
+
+					if part.filesystem == "efi" {
+						debug!("Setting esp on for efi partition");
+						trace!("parted -s {disk:?} set {i} esp on");
+						cmd_lib::run_cmd!(parted -s $disk set $i esp on 2>&1)?;
+					}
+
+					if let Some(label) = &part.label {
+						debug!(label, "Setting label");
+						trace!("parted -s {disk:?} name {i} {label}");
+						cmd_lib::run_cmd!(parted -s $disk name $i $label 2>&1)?;
+					}
+				} else {
+					let mbr_type = format!("{:#04x}", part.mbr_type());
+					debug!(mbr_type, "Setting MBR partition type");
+					trace!("parted -s {disk:?} type {i} {mbr_type}");
+					cmd_lib::run_cmd!(parted -s $disk type $i $mbr_type 2>&1)?;
+
+					if part.mbr_bootable() {
+						trace!("parted -s {disk:?} set {i} boot on");
+						cmd_lib::run_cmd!(parted -s $disk set $i boot on 2>&1)?;
+					}
+				}
 
-			let end_string = part.size.map_or("100%".to_string(), |size| {
-				// create partition with size
-				last_end += size.as_u64();
+				trace!("Refreshing partition tables");
+				let _ = cmd_lib::run_cmd!(partprobe); // comes with parted supposedly
+
+				// the filesystem goes on the opened LUKS device for encrypted partitions
+				let devname = if let Some(encryption) = &part.encryption {
+					if part.filesystem == "efi" || part.filesystem == "none" {
+						bail!(
+							"Partition {devname} with filesystem `{}` cannot be encrypted",
+							part.filesystem
+						);
+					}
+					encryption.format(&devname, &luks_mapper(i))?;
+					self.fs_device(disk, i)
+				} else {
+					devname
+				};
+
+				// time to format the filesystem
+				let fsname = &part.filesystem;
+				if fsname == "lvm" {
+					trace!("pvcreate -y {devname}");
+					cmd_lib::run_cmd!(pvcreate -y $devname 2>&1)?;
+				} else {
+					mkfs(fsname, &devname, &part.mkfs)?;
+				}
 
-				// remove space for partition table
-				format!("{}MiB", last_end / 1024 / 1024)
-			});
+				if fsname == "btrfs" && !part.subvolumes.is_empty() {
+					part.create_subvolumes(&devname)?;
+				}
 
-			// not going to change this for now though, but will revisit
-			debug!(start = start_string, end = end_string, "Creating partition");
-			trace!("parted -s {disk:?} mkpart primary fat32 {start_string} {end_string}");
-			cmd_lib::run_cmd!(parted -s $disk mkpart primary fat32 $start_string $end_string 2>&1)?;
+				Result::<_>::Ok(())
+			},
+		)?;
 
-			let part_type_uuid = part.partition_type.uuid(target_arch);
+		if !hybrid.is_empty() {
+			write_hybrid_mbr(disk, &hybrid)?;
+		}
 
-			debug!("Setting partition type");
-			trace!("parted -s {disk:?} type {i} {part_type_uuid}");
-			cmd_lib::run_cmd!(parted -s $disk type $i $part_type_uuid 2>&1)?;
+		self.create_volume_groups(disk)
+	}
 
-			if let Some(flags) = &part.flags {
-				debug!("Setting partition attribute flags");
+	/// Partition alignment in bytes
+	fn alignment(&self) -> Result<u64> {
+		let align = self.alignment.map_or(1024 * 1024, |a| a.as_u64());
+		if align == 0 || !align.is_multiple_of(512) {
+			bail!("Partition alignment {align} is not a multiple of 512 bytes");
+		}
+		Ok(align)
+	}
 
-				for flag in flags {
-					let position = flag.flag_position();
-					trace!("sgdisk -A {i}:set:{position} {disk:?}");
-					cmd_lib::run_cmd!(sgdisk -A $i:set:$position $disk 2>&1)?;
-				}
+	/// Byte ranges `[start, end)` of the partitions on a disk of `disk_size` bytes, starting at
+	/// multiples of the alignment. Errors if they do not fit on the disk
+	pub fn partition_bounds(&self, disk_size: u64, label: DiskLabel) -> Result<Vec<(u64, u64)>> {
+		let align = self.alignment()?;
+		let std::ops::Range { start: first, end: usable } = label.usable(disk_size);
+
+		let mut next = first;
+		let mut bounds = vec![];
+		for (i, part) in self.partitions.iter().enumerate() {
+			let start = next.next_multiple_of(align);
+			let end = match part.size {
+				Some(size) => start + size.bytes(disk_size),
+				None if i + 1 == self.partitions.len() => usable / align * align,
+				None => bail!("Only the last partition can take the rest of the disk, partition {} needs a size", i + 1),
+			};
+			if end > usable || end <= start {
+				bail!(
+					"Partition {} does not fit on the disk of {} bytes, it would end at byte {end}",
+					i + 1,
+					disk_size
+				);
 			}
+			bounds.push((start, end));
+			next = end;
+		}
+		Ok(bounds)
+	}
 
-			if part.filesystem == "efi" {
-				debug!("Setting esp on for efi partition");
-				trace!("parted -s {disk:?} set {i} esp on");
-				cmd_lib::run_cmd!(parted -s $disk set $i esp on 2>&1)?;
-			}
+	/// Disk size for `size: auto`, fitting the partitions with a size and `content` bytes plus
+	/// the headroom in the partition without one
+	pub fn auto_size(&self, label: DiskLabel, content: u64) -> Result<u64> {
+		if self.partitions.iter().any(|p| matches!(p.size, Some(PartitionSize::Percent(_)))) {
+			bail!("Percentage partition sizes need a fixed disk size, not `auto`");
+		}
+		let align = self.alignment()?;
+		let rest = content + content / 100 * u64::from(self.headroom);
 
-			if let Some(label) = &part.label {
-				debug!(label, "Setting label");
-				trace!("parted -s {disk:?} name {i} {label}");
-				cmd_lib::run_cmd!(parted -s $disk name $i $label 2>&1)?;
-			}
+		let (mut end, backup) = label.table_sizes();
+		for part in &self.partitions {
+			let size = match part.size {
+				Some(size) => size.bytes(0),
+				None => (rest + self.lvm_fixed_size(part)).next_multiple_of(align),
+			};
+			end = end.next_multiple_of(align) + size;
+		}
+		Ok((end + backup).next_multiple_of(align))
+	}
 
-			trace!("Refreshing partition tables");
-			let _ = cmd_lib::run_cmd!(partprobe); // comes with parted supposedly
+	/// Space taken by the logical volumes with a size in the volume group `part` belongs to,
+	/// including the LVM metadata and rounding up to 4 MiB extents
+	fn lvm_fixed_size(&self, part: &Partition) -> u64 {
+		const EXTENT: u64 = 4 * 1024 * 1024;
+		let vg = part.volume_group.as_ref().and_then(|n| self.lvm.iter().find(|g| &g.name == n));
+		vg.map_or(0, |vg| {
+			EXTENT
+				+ vg.volumes
+					.iter()
+					.map(|lv| lv.size.map_or(0, |s| s.as_u64()) + EXTENT)
+					.sum::<u64>()
+		})
+	}
 
-			// time to format the filesystem
-			let fsname = &part.filesystem;
-			// Some stupid hackery checks for the args of mkfs.fat
-			debug!(fsname, "Formatting partition");
-			if fsname == "efi" {
-				trace!("mkfs.fat -F32 {devname}");
-				cmd_lib::run_cmd!(mkfs.fat -F32 $devname 2>&1)?;
-			} else if fsname == "none" {
-			} else {
-				trace!("mkfs.{fsname} {devname}");
-				cmd_lib::run_cmd!(mkfs.$fsname $devname 2>&1)?;
-			}
+	/// Partitions mirrored in a hybrid MBR, as `(partition number, MBR type, bootable)`
+	fn hybrid_mbr_partitions(&self) -> Result<Vec<(usize, u8, bool)>> {
+		let hybrid = (self.partitions.iter().enumerate())
+			.filter(|(_, p)| p.mbr_type.is_some())
+			.map(|(i, p)| (i + 1, p.mbr_type(), p.mbr_bootable()))
+			.collect::<Vec<_>>();
+		if hybrid.is_empty() || hybrid.len() > 3 {
+			bail!("Hybrid MBRs mirror 1 to 3 partitions, set `mbr_type` on the ones to include");
+		}
+		Ok(hybrid)
+	}
 
-			Result::<_>::Ok((i + 1, last_end))
-		})?;
+	/// Checks that every volume group has physical volumes and every `lvm` partition a volume group
+	fn validate_lvm(&self) -> Result<()> {
+		for part in self.partitions.iter().filter(|p| p.filesystem == "lvm") {
+			bail_let!(Some(vg) = &part.volume_group => "Partitions with the `lvm` filesystem need a `volume_group`");
+			if !self.lvm.iter().any(|v| &v.name == vg) {
+				bail!("Volume group {vg} is not defined in `lvm`");
+			}
+		}
+		for vg in &self.lvm {
+			if !self.partitions.iter().any(|p| p.volume_group.as_ref() == Some(&vg.name)) {
+				bail!("Volume group {} has no partitions with the `lvm` filesystem", vg.name);
+			}
+		}
+		Ok(())
+	}
 
+	/// Creates the volume groups and their logical volumes on the physical volumes from [`Self::apply`]
+	fn create_volume_groups(&self, disk: &Path) -> Result<()> {
+		for vg in &self.lvm {
+			let name = &vg.name;
+			let pvs = (self.partitions.iter().enumerate())
+				.filter(|(_, p)| p.volume_group.as_ref() == Some(name))
+				.map(|(i, _)| self.fs_device(disk, i + 1))
+				.collect::<Vec<_>>();
+
+			info!(name, ?pvs, "Creating volume group");
+			cmd_lib::run_cmd!(vgcreate -y $name $[pvs] 2>&1)?;
+
+			for lv in &vg.volumes {
+				let lv_name = &lv.name;
+				let size = lv.size_args()?;
+				debug!(vg = name, lv = lv_name, ?size, "Creating logical volume");
+				cmd_lib::run_cmd!(lvcreate -y -n $lv_name $[size] $name 2>&1)?;
+				mkfs(&lv.filesystem, &format!("/dev/{name}/{lv_name}"), &lv.mkfs)?;
+			}
+		}
 		Ok(())
 	}
 }
@@ -554,30 +2326,57 @@ fn test_partlay() {
 		label: Some("EFI".to_string()),
 		partition_type: PartitionType::Esp,
 		flags: None,
-		size: Some(ByteSize::mib(100)),
+		size: Some(PartitionSize::Bytes(ByteSize::mib(100))),
 		filesystem: "efi".to_string(),
 		mountpoint: "/boot/efi".to_string(),
 		subvolumes: vec![],
+		default_subvolume: None,
+		compression: None,
+		encryption: None,
+		volume_group: None,
+		verity: false,
+		mount: MountSettings::default(),
+		mkfs: MkfsOptions::default(),
+		mbr_type: None,
+		bootable: None,
 	});
 
 	partlay.add_partition(Partition {
 		label: Some("boot".to_string()),
 		partition_type: PartitionType::Xbootldr,
 		flags: None,
-		size: Some(ByteSize::gib(100)),
+		size: Some(PartitionSize::Bytes(ByteSize::gib(100))),
 		filesystem: "ext4".to_string(),
 		mountpoint: "/boot".to_string(),
 		subvolumes: vec![],
+		default_subvolume: None,
+		compression: None,
+		encryption: None,
+		volume_group: None,
+		verity: false,
+		mount: MountSettings::default(),
+		mkfs: MkfsOptions::default(),
+		mbr_type: None,
+		bootable: None,
 	});
 
 	partlay.add_partition(Partition {
 		label: Some("ROOT".to_string()),
 		partition_type: PartitionType::Root,
 		flags: None,
-		size: Some(ByteSize::gib(100)),
+		size: Some(PartitionSize::Bytes(ByteSize::gib(100))),
 		filesystem: "ext4".to_string(),
 		mountpoint: "/".to_string(),
 		subvolumes: vec![],
+		default_subvolume: None,
+		compression: None,
+		encryption: None,
+		volume_group: None,
+		verity: false,
+		mount: MountSettings::default(),
+		mkfs: MkfsOptions::default(),
+		mbr_type: None,
+		bootable: None,
 	});
 
 	for (i, part) in partlay.partitions.iter().enumerate() {
@@ -612,10 +2411,19 @@ fn test_partlay() {
 				label: Some("ROOT".to_string()),
 				partition_type: PartitionType::Root,
 				flags: None,
-				size: Some(ByteSize::gib(100)),
+				size: Some(PartitionSize::Bytes(ByteSize::gib(100))),
 				filesystem: "ext4".to_string(),
 				mountpoint: "/".to_string(),
 				subvolumes: vec![],
+				default_subvolume: None,
+				compression: None,
+				encryption: None,
+				volume_group: None,
+				verity: false,
+				mount: MountSettings::default(),
+				mkfs: MkfsOptions::default(),
+				mbr_type: None,
+				bootable: None,
 			},
 		),
 		(
@@ -624,10 +2432,19 @@ fn test_partlay() {
 				label: Some("boot".to_string()),
 				partition_type: PartitionType::Xbootldr,
 				flags: None,
-				size: Some(ByteSize::gib(100)),
+				size: Some(PartitionSize::Bytes(ByteSize::gib(100))),
 				filesystem: "ext4".to_string(),
 				mountpoint: "/boot".to_string(),
 				subvolumes: vec![],
+				default_subvolume: None,
+				compression: None,
+				encryption: None,
+				volume_group: None,
+				verity: false,
+				mount: MountSettings::default(),
+				mkfs: MkfsOptions::default(),
+				mbr_type: None,
+				bootable: None,
 			},
 		),
 		(
@@ -636,10 +2453,19 @@ fn test_partlay() {
 				label: Some("EFI".to_string()),
 				partition_type: PartitionType::Esp,
 				flags: None,
-				size: Some(ByteSize::mib(100)),
+				size: Some(PartitionSize::Bytes(ByteSize::mib(100))),
 				filesystem: "efi".to_string(),
 				mountpoint: "/boot/efi".to_string(),
 				subvolumes: vec![],
+				default_subvolume: None,
+				compression: None,
+				encryption: None,
+				volume_group: None,
+				verity: false,
+				mount: MountSettings::default(),
+				mkfs: MkfsOptions::default(),
+				mbr_type: None,
+				bootable: None,
 			},
 		),
 	];
@@ -652,15 +2478,24 @@ fn test_partlay() {
 
 // TODO: add more partitions from https://uapi-group.org/specifications/specs/discoverable_partitions_specification/#partition-names ?
 
-/// Represents GPT partition types which can be used, a subset of https://uapi-group.org/specifications/specs/discoverable_partitions_specification.
+/// Represents GPT partition types which can be used, from https://uapi-group.org/specifications/specs/discoverable_partitions_specification.
 /// If the partition type you need isn't in the enum, please file an issue and use the GUID variant.
 /// This is not the filesystem which is formatted on the partition.
 #[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 pub enum PartitionType {
-	// TODO: we need a global arch option in Katsu
 	/// Root partition for the target architecture of the build if set, otherwise defaults to the local architecture
 	Root,
+	/// `/usr` partition for the target architecture
+	Usr,
+	/// dm-verity hash partition of the root partition for the target architecture
+	RootVerity,
+	/// dm-verity hash partition of the `/usr` partition for the target architecture
+	UsrVerity,
+	/// dm-verity signature partition of the root partition for the target architecture
+	RootVeritySig,
+	/// dm-verity signature partition of the `/usr` partition for the target architecture
+	UsrVeritySig,
 	/// Root partition for ARM64
 	RootArm64,
 	/// Root partition for x86_64
@@ -671,8 +2506,20 @@ pub enum PartitionType {
 	Xbootldr,
 	/// Swap partition
 	Swap,
+	/// Home partition, mounted to `/home`
+	Home,
+	/// Server data partition, mounted to `/srv`
+	Srv,
+	/// Variable data partition, mounted to `/var`
+	Var,
+	/// Temporary data partition, mounted to `/var/tmp`
+	VarTmp,
+	/// Per-user home partition, used by systemd-homed
+	UserHome,
 	/// A generic partition that carries a Linux filesystem
 	LinuxGeneric,
+	/// LVM physical volume
+	LinuxLvm,
 	/// MBR header partition for grub-install
 	BiosGrub,
 	/// An arbitrary GPT partition type GUID/UUIDv4
@@ -680,29 +2527,249 @@ pub enum PartitionType {
 	Guid(uuid::Uuid),
 }
 
+/// Architecture specific partition type GUIDs from the Discoverable Partitions Specification, in the order
+/// root, usr, root verity, usr verity, root verity signature, usr verity signature
+#[rustfmt::skip]
+const DPS_ARCH_TYPES: &[(&str, [&str; 6])] = &[
+	("alpha", [
+		"6523f8ae-3eb1-4e2a-a05a-18b695ae656f", "e18cf08c-33ec-4c0d-8246-c6c6fb3da024",
+		"fc56d9e9-e6e5-4c06-be32-e74407ce09a5", "8cce0d25-c0d0-4a44-bd87-46331bf1df67",
+		"d46495b7-a053-414f-80f7-700c99921ef8", "5c6e1c76-076a-457a-a0fe-f3b4cd21ce6e",
+	]),
+	("arc", [
+		"d27f46ed-2919-4cb8-bd25-9531f3c16534", "7978a683-6316-4922-bbee-38bff5a2fecc",
+		"24b2d975-0f97-4521-afa1-cd531e421b8d", "fca0598c-d880-4591-8c16-4eda05c7347c",
+		"143a70ba-cbd3-4f06-919f-6c05683a78bc", "94f9a9a1-9971-427a-a400-50cb297f0f35",
+	]),
+	("arm", [
+		"69dad710-2ce4-4e3c-b16c-21a1d49abed3", "7d0359a3-02b3-4f0a-865c-654403e70625",
+		"7386cdf2-203c-47a9-a498-f2ecce45a2d6", "c215d751-7bcd-4649-be90-6627490a4c05",
+		"42b0455f-eb11-491d-98d3-56145ba9d037", "d7ff812f-37d1-4902-a810-d76ba57b975a",
+	]),
+	("arm64", [
+		"b921b045-1df0-41c3-af44-4c6f280d3fae", "b0e01050-ee5f-4390-949a-9101b17104e9",
+		"df3300ce-d69f-4c92-978c-9bfb0f38d820", "6e11a4e7-fbca-4ded-b9e9-e1a512bb664e",
+		"6db69de6-29f4-4758-a7a5-962190f00ce3", "c23ce4ff-44bd-4b00-b2d4-b41b3419e02a",
+	]),
+	("ia64", [
+		"993d8d3d-f80e-4225-855a-9daf8ed7ea97", "4301d2a6-4e3b-4b2a-bb94-9e0b2c4225ea",
+		"86ed10d5-b607-45bb-8957-d350f23d0571", "6a491e03-3be7-4545-8e38-83320e0ea880",
+		"e98b36ee-32ba-4882-9b12-0ce14655f46a", "8de58bc2-2a43-460d-b14e-a76e4a17b47f",
+	]),
+	("loongarch64", [
+		"77055800-792c-4f94-b39a-98c91b762bb6", "e611c702-575c-4cbe-9a46-434fa0bf7e3f",
+		"f3393b22-e9af-4613-a948-9d3bfbd0c535", "f46b2c26-59ae-48f0-9106-c50ed47f673d",
+		"5afb67eb-ecc8-4f85-ae8e-ac1e7c50e7d0", "b024f315-d330-444c-8461-44bbde524e99",
+	]),
+	("mips", [
+		"e9434544-6e2c-47cc-bae2-12d6deafb44c", "773b2abc-2a99-4398-8bf5-03baac40d02b",
+		"7a430799-f711-4c7e-8e5b-1d685bd48607", "6e5a1bc8-d223-49b7-bca8-37a5fcceb996",
+		"bba210a2-9c5d-45ee-9e87-ff2ccbd002d0", "97ae158d-f216-497b-8057-f7f905770f54",
+	]),
+	("mips64", [
+		"d113af76-80ef-41b4-bdb6-0cff4d3d4a25", "57e13958-7331-4365-8e6e-35eeee17c61b",
+		"579536f8-6a33-4055-a95a-df2d5e2c42a8", "81cf9d90-7458-4df4-8dcf-c8a3a404f09b",
+		"43ce94d4-0f3d-4999-8250-b9deafd98e6e", "05816ce2-dd40-4ac6-a61d-37d32dc1ba7d",
+	]),
+	("mips-le", [
+		"37c58c8a-d913-4156-a25f-48b1b64e07f0", "0f4868e9-9952-4706-979f-3ed3a473e947",
+		"d7d150d2-2a04-4a33-8f12-16651205ff7b", "46b98d8d-b55c-4e8f-aab3-37fca7f80752",
+		"c919cc1f-4456-4eff-918c-f75e94525ca5", "3e23ca0b-a4bc-4b4e-8087-5ab6a26aa8a9",
+	]),
+	("mips64-le", [
+		"700bda43-7a34-4507-b179-eeb93d7a7ca3", "c97c1f32-ba06-40b4-9f22-236061b08aa8",
+		"16b417f8-3e06-4f57-8dd2-9b5232f41aa6", "3c3d61fe-b5f3-414d-bb71-8739a694a4ef",
+		"904e58ef-5c65-4a31-9c57-6af5fc7c5de7", "f2c2c7ee-adcc-4351-b5c6-ee9816b66e16",
+	]),
+	("parisc", [
+		"1aacdb3b-5444-4138-bd9e-e5c2239b2346", "dc4a4480-6917-4262-a4ec-db93849c9f25",
+		"d212a430-fbc5-49f9-a983-a7feef2b8d0e", "5843d618-ec37-48d7-9f12-cea8e08768b2",
+		"15de6170-65d3-431c-916e-b0dcd8393f25", "450dd7d1-3224-45ec-9cf2-a43a346d71ee",
+	]),
+	("ppc", [
+		"1de3f1ef-fa98-47b5-8dcd-4a860a654d78", "7d14fec5-cc71-415d-9d6c-06bf0b3c3eaf",
+		"98cfe649-1588-46dc-b2f0-add147424925", "df765d00-270e-49e5-bc75-f47bb2118b09",
+		"1b31b5aa-add9-463a-b2ed-bd467fc857e7", "7007891d-d371-4a80-86a4-5cb875b9302e",
+	]),
+	("ppc64", [
+		"912ade1d-a839-4913-8964-a10eee08fbd2", "2c9739e2-f068-46b3-9fd0-01c5a9afbcca",
+		"9225a9a3-3c19-4d89-b4f6-eeff88f17631", "bdb528a5-a259-475f-a87d-da53fa736a07",
+		"f5e2c20c-45b2-4ffa-bce9-2a60737e1aaf", "0b888863-d7f8-4d9e-9766-239fce4d58af",
+	]),
+	("ppc64-le", [
+		"c31c45e6-3f39-412e-80fb-4809c4980599", "15bb03af-77e7-4d4a-b12b-c0d084f7491c",
+		"906bd944-4589-4aae-a4e4-dd983917446a", "ee2b9983-21e8-4153-86d9-b6901a54d1ce",
+		"d4a236e7-e873-4c07-bf1d-bf6cf7f1c3c6", "c8bfbd1e-268e-4521-8bba-bf314c399557",
+	]),
+	("riscv32", [
+		"60d5a7fe-8e7d-435c-b714-3dd8162144e1", "b933fb22-5c3f-4f91-af90-e2bb0fa50702",
+		"ae0253be-1167-4007-ac68-43926c14c5de", "cb1ee4e3-8cd0-4136-a0a4-aa61a32e8730",
+		"3a112a75-8729-4380-b4cf-764d79934448", "c3836a13-3137-45ba-b583-b16c50fe5eb4",
+	]),
+	("riscv64", [
+		"72ec70a6-cf74-40e6-bd49-4bda08e8f224", "beaec34b-8442-439b-a40b-984381ed097d",
+		"b6ed5582-440b-4209-b8da-5ff7c419ea3d", "8f1056be-9b05-47c4-81d6-be53128e5b54",
+		"efe0f087-ea8d-4469-821a-4c2a96a8386a", "d2f9000a-7a18-453f-b5cd-4d32f77a7b32",
+	]),
+	("s390", [
+		"08a7acea-624c-4a20-91e8-6e0fa67d23f9", "cd0f869b-d0fb-4ca0-b141-9ea87cc78d66",
+		"7ac63b47-b25c-463b-8df8-b4a94e6c90e1", "b663c618-e7bc-4d6d-90aa-11b756bb1797",
+		"3482388e-4254-435a-a241-766a065f9960", "17440e4f-a8d0-467f-a46e-3912ae6ef2c5",
+	]),
+	("s390x", [
+		"5eead9a9-fe09-4a1e-a1d7-520d00531306", "8a4f5770-50aa-4ed3-874a-99b710db6fea",
+		"b325bfbe-c7be-4ab8-8357-139e652d2f6b", "31741cc4-1a2a-4111-a581-e00b447d2d06",
+		"c80187a5-73a3-491a-901a-017c3fa953e9", "3f324816-667b-46ae-86ee-9b0c0c6c11b4",
+	]),
+	("tilegx", [
+		"c50cdd70-3862-4cc3-90e1-809a8c93ee2c", "55497029-c7c1-44cc-aa39-815ed1558630",
+		"966061ec-28e4-4b2e-b4a5-1f0a825a1d84", "2fb4bf56-07fa-42da-8132-6b139f2026ae",
+		"b3671439-97b0-4a53-90f7-2d5a8f3ad47b", "4ede75e2-6ccc-4cc8-b9c7-70334b087510",
+	]),
+	("x86", [
+		"44479540-f297-41b2-9af7-d131d5f0458a", "75250d76-8cc6-458e-bd66-bd47cc81a812",
+		"d13c5d3b-b5d1-422a-b29f-9454fdc89d76", "8f461b0d-14ee-4e81-9aa9-049b6fb97abd",
+		"5996fc05-109c-48de-808b-23fa0830b676", "974a71c0-de41-43c3-be5d-5c5ccd1ad2c0",
+	]),
+	("x86-64", [
+		"4f68bce3-e8cd-4db1-96e7-fbcaf984b709", "8484680c-9521-48c6-9c11-b0720656f69e",
+		"2c7357ed-ebd2-46d9-aec1-23d437ec2bf5", "77ff5f63-e7b6-4633-acf4-1565b864c0e6",
+		"41092b05-9fc8-4523-994f-2def0408b176", "e7bb33fb-06cf-4e81-8273-e543b413e2e2",
+	]),
+];
+
+/// Maps a target architecture as used by RPM, DNF or Rust to its name in the Discoverable Partitions Specification
+fn dps_arch(target_arch: &str) -> Option<&'static str> {
+	Some(match target_arch {
+		"alpha" => "alpha",
+		"arc" => "arc",
+		"arm" | "armv7" | "armv7l" | "armv7hl" | "armhfp" => "arm",
+		"aarch64" | "arm64" => "arm64",
+		"ia64" => "ia64",
+		"loongarch64" => "loongarch64",
+		"mips" => "mips",
+		"mipsel" | "mips-le" => "mips-le",
+		"mips64" => "mips64",
+		"mips64el" | "mips64-le" => "mips64-le",
+		"parisc" | "hppa" => "parisc",
+		"ppc" | "powerpc" => "ppc",
+		"ppc64" => "ppc64",
+		"ppc64le" | "ppc64-le" | "powerpc64le" => "ppc64-le",
+		// Rust calls both endiannesses powerpc64
+		"powerpc64" if cfg!(target_endian = "little") => "ppc64-le",
+		"powerpc64" => "ppc64",
+		"riscv32" => "riscv32",
+		"riscv64" => "riscv64",
+		"s390" => "s390",
+		"s390x" => "s390x",
+		"tilegx" => "tilegx",
+		"x86" | "i386" | "i486" | "i586" | "i686" => "x86",
+		"x86_64" | "amd64" | "x86-64" => "x86-64",
+		_ => return None,
+	})
+}
+
 impl PartitionType {
 	/// Get the GPT partition type GUID
-	fn uuid(&self, target_arch: &str) -> String {
+	fn uuid(&self, target_arch: &str) -> Result<String> {
 		// https://uapi-group.org/specifications/specs/discoverable_partitions_specification/#partition-names
-		match self {
-			PartitionType::Root => {
-				return match target_arch {
-					"x86_64" => PartitionType::RootX86_64.uuid(target_arch),
-					"aarch64" => PartitionType::RootArm64.uuid(target_arch),
-					_ => unimplemented!(),
+		let column = match self {
+			PartitionType::Root => 0,
+			PartitionType::Usr => 1,
+			PartitionType::RootVerity => 2,
+			PartitionType::UsrVerity => 3,
+			PartitionType::RootVeritySig => 4,
+			PartitionType::UsrVeritySig => 5,
+			PartitionType::RootArm64 => return PartitionType::Root.uuid("aarch64"),
+			PartitionType::RootX86_64 => return PartitionType::Root.uuid("x86_64"),
+			PartitionType::Guid(guid) => return Ok(guid.to_string()),
+			_ => {
+				return Ok(match self {
+					PartitionType::Esp => "c12a7328-f81f-11d2-ba4b-00a0c93ec93b",
+					PartitionType::Xbootldr => "bc13c2ff-59e6-4262-a352-b275fd6f7172",
+					PartitionType::Swap => "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f",
+					PartitionType::Home => "933ac7e1-2eb4-4f13-b844-0e14e2aef915",
+					PartitionType::Srv => "3b8f8425-20e0-4f3b-907f-1a25a76f98e8",
+					PartitionType::Var => "4d21b016-b534-45c2-a9fb-5c16e091fd2d",
+					PartitionType::VarTmp => "7ec6f557-3bc5-4aca-b293-16ef5df639d1",
+					PartitionType::UserHome => "773f91ef-66d4-49b5-bd83-d683bf40ad16",
+					PartitionType::LinuxGeneric => "0fc63daf-8483-4772-8e79-3d69d8477de4",
+					PartitionType::LinuxLvm => "e6d6d379-f507-44c2-a23c-238f2a3df928",
+					PartitionType::BiosGrub => "21686148-6449-6E6F-744E-656564454649",
+					_ => unreachable!(),
 				}
+				.to_string())
 			},
-			PartitionType::RootArm64 => "b921b045-1df0-41c3-af44-4c6f280d3fae",
-			PartitionType::RootX86_64 => "4f68bce3-e8cd-4db1-96e7-fbcaf984b709",
-			PartitionType::Esp => "c12a7328-f81f-11d2-ba4b-00a0c93ec93b",
-			PartitionType::Xbootldr => "bc13c2ff-59e6-4262-a352-b275fd6f7172",
-			PartitionType::Swap => "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f",
-			PartitionType::LinuxGeneric => "0fc63daf-8483-4772-8e79-3d69d8477de4",
-			PartitionType::BiosGrub => "21686148-6449-6E6F-744E-656564454649",
-			PartitionType::Guid(guid) => return guid.to_string(),
+		};
+
+		bail_let!(
+			Some((_, guids)) = dps_arch(target_arch).and_then(|arch| DPS_ARCH_TYPES.iter().find(|(a, _)| *a == arch))
+				=> "Architecture {target_arch} has no {self:?} partition type in the Discoverable Partitions Specification"
+		);
+		Ok(guids[column].to_string())
+	}
+}
+
+#[test]
+fn test_partition_type_uuid() {
+	use PartitionType::*;
+	let cases = [
+		(Root, "x86_64", "4f68bce3-e8cd-4db1-96e7-fbcaf984b709"),
+		(Root, "aarch64", "b921b045-1df0-41c3-af44-4c6f280d3fae"),
+		(Root, "i686", "44479540-f297-41b2-9af7-d131d5f0458a"),
+		(Root, "armv7hl", "69dad710-2ce4-4e3c-b16c-21a1d49abed3"),
+		(Root, "riscv64", "72ec70a6-cf74-40e6-bd49-4bda08e8f224"),
+		(Root, "ppc64le", "c31c45e6-3f39-412e-80fb-4809c4980599"),
+		(Root, "s390x", "5eead9a9-fe09-4a1e-a1d7-520d00531306"),
+		(Root, "loongarch64", "77055800-792c-4f94-b39a-98c91b762bb6"),
+		(Root, "mips", "e9434544-6e2c-47cc-bae2-12d6deafb44c"),
+		(Root, "mipsel", "37c58c8a-d913-4156-a25f-48b1b64e07f0"),
+		(Root, "mips64", "d113af76-80ef-41b4-bdb6-0cff4d3d4a25"),
+		(Root, "mips64el", "700bda43-7a34-4507-b179-eeb93d7a7ca3"),
+		(Usr, "mips", "773b2abc-2a99-4398-8bf5-03baac40d02b"),
+		(Usr, "mips64el", "c97c1f32-ba06-40b4-9f22-236061b08aa8"),
+		(Usr, "x86_64", "8484680c-9521-48c6-9c11-b0720656f69e"),
+		(Usr, "aarch64", "b0e01050-ee5f-4390-949a-9101b17104e9"),
+		(Usr, "riscv64", "beaec34b-8442-439b-a40b-984381ed097d"),
+		(RootVerity, "x86_64", "2c7357ed-ebd2-46d9-aec1-23d437ec2bf5"),
+		(RootVerity, "aarch64", "df3300ce-d69f-4c92-978c-9bfb0f38d820"),
+		(RootVerity, "mips", "7a430799-f711-4c7e-8e5b-1d685bd48607"),
+		(RootVerity, "mipsel", "d7d150d2-2a04-4a33-8f12-16651205ff7b"),
+		(UsrVerity, "x86_64", "77ff5f63-e7b6-4633-acf4-1565b864c0e6"),
+		(UsrVerity, "aarch64", "6e11a4e7-fbca-4ded-b9e9-e1a512bb664e"),
+		(RootVeritySig, "x86_64", "41092b05-9fc8-4523-994f-2def0408b176"),
+		(RootVeritySig, "aarch64", "6db69de6-29f4-4758-a7a5-962190f00ce3"),
+		(UsrVeritySig, "x86_64", "e7bb33fb-06cf-4e81-8273-e543b413e2e2"),
+		(UsrVeritySig, "aarch64", "c23ce4ff-44bd-4b00-b2d4-b41b3419e02a"),
+		(UsrVeritySig, "mips64", "05816ce2-dd40-4ac6-a61d-37d32dc1ba7d"),
+		(UsrVeritySig, "mips64el", "f2c2c7ee-adcc-4351-b5c6-ee9816b66e16"),
+		(RootArm64, "x86_64", "b921b045-1df0-41c3-af44-4c6f280d3fae"),
+		(RootX86_64, "aarch64", "4f68bce3-e8cd-4db1-96e7-fbcaf984b709"),
+		(Esp, "riscv64", "c12a7328-f81f-11d2-ba4b-00a0c93ec93b"),
+		(Xbootldr, "x86_64", "bc13c2ff-59e6-4262-a352-b275fd6f7172"),
+		(Swap, "x86_64", "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f"),
+		(Home, "x86_64", "933ac7e1-2eb4-4f13-b844-0e14e2aef915"),
+		(Srv, "x86_64", "3b8f8425-20e0-4f3b-907f-1a25a76f98e8"),
+		(Var, "x86_64", "4d21b016-b534-45c2-a9fb-5c16e091fd2d"),
+		(VarTmp, "x86_64", "7ec6f557-3bc5-4aca-b293-16ef5df639d1"),
+		(UserHome, "x86_64", "773f91ef-66d4-49b5-bd83-d683bf40ad16"),
+		(LinuxGeneric, "x86_64", "0fc63daf-8483-4772-8e79-3d69d8477de4"),
+	];
+	for (partition_type, arch, guid) in cases {
+		assert_eq!(partition_type.uuid(arch).unwrap(), guid, "{partition_type:?} on {arch}");
+	}
+
+	// every GUID in the table is valid and unique
+	let mut seen = std::collections::HashSet::new();
+	for (arch, guids) in DPS_ARCH_TYPES {
+		assert_eq!(dps_arch(arch), Some(*arch));
+		for guid in guids {
+			assert!(uuid::Uuid::parse_str(guid).is_ok(), "{guid} for {arch}");
+			assert!(seen.insert(guid), "{guid} for {arch} is a duplicate");
 		}
-		.to_string()
 	}
+
+	assert!(Root.uuid("sparc64").is_err());
 }
 
 /// Represents GPT partition attrbite flags which can be used, from https://uapi-group.org/specifications/specs/discoverable_partitions_specification/#partition-attribute-flags.
@@ -715,6 +2782,8 @@ pub enum PartitionFlag {
 	ReadOnly,
 	/// Enable automatically growing the underlying file system when mounted
 	GrowFs,
+	/// Mark the partition as legacy BIOS bootable, U-Boot looks for `extlinux.conf` on these first
+	LegacyBiosBootable,
 	/// An arbitrary GPT attribute flag position, 0 - 63
 	#[serde(untagged)]
 	FlagPosition(u8),
@@ -728,6 +2797,7 @@ impl PartitionFlag {
 			PartitionFlag::NoAuto => 63,
 			PartitionFlag::ReadOnly => 60,
 			PartitionFlag::GrowFs => 59,
+			PartitionFlag::LegacyBiosBootable => 2,
 			PartitionFlag::FlagPosition(position @ 0..=63) => *position,
 			_ => unimplemented!(),
 		}
@@ -743,8 +2813,9 @@ pub struct Partition {
 	/// GPT partition attribute flags to add
 	// todo: maybe represent this as a bitflag number, parted consumes the positions so I'm doing this for now
 	pub flags: Option<Vec<PartitionFlag>>,
-	/// If not specified, the partition will be created at the end of the disk (100%)
-	pub size: Option<ByteSize>,
+	/// Size in bytes, sectors (`2048s`) or percent of the disk (`25%`).
+	/// If not specified, the partition takes the rest of the disk, only the last one can do that
+	pub size: Option<PartitionSize>,
 	/// Filesystem of the partition
 	pub filesystem: String,
 	/// The mountpoint of the partition
@@ -755,14 +2826,467 @@ pub struct Partition {
 	/// Will only be used if the filesystem is btrfs
 	#[serde(default)]
 	pub subvolumes: Vec<BtrfsSubvolume>,
+
+	/// Subvolume to set as the default, mounted when no `subvol=` option is given.
+	/// Will only be used if the filesystem is btrfs
+	#[serde(default)]
+	pub default_subvolume: Option<String>,
+
+	/// Compression algorithm for the `compress=` mount option, e.g. `zstd:1`.
+	/// Will only be used if the filesystem is btrfs
+	#[serde(default)]
+	pub compression: Option<String>,
+
+	/// Encrypt the partition with LUKS2
+	#[serde(default)]
+	pub encryption: Option<Encryption>,
+
+	/// Volume group this partition is a physical volume of, requires the `lvm` filesystem
+	#[serde(default)]
+	pub volume_group: Option<String>,
+
+	/// Protect the partition with dm-verity, only for `/` and `/usr`. A hash partition is added
+	/// after it and the partition is read-only once the root builder finishes
+	#[serde(default)]
+	pub verity: bool,
+
+	/// MBR partition type code, e.g. `0x0c` for FAT32 or `0xef` for an ESP.
+	/// Derived from the filesystem on `msdos` disks, required to be in a hybrid MBR
+	#[serde(default)]
+	pub mbr_type: Option<u8>,
+	/// Set the MBR boot flag, by default only on `efi` partitions. Not used on GPT disks
+	#[serde(default)]
+	pub bootable: Option<bool>,
+
+	#[serde(flatten)]
+	pub mkfs: MkfsOptions,
+	#[serde(flatten)]
+	pub mount: MountSettings,
+}
+
+/// LUKS2 encryption for a partition
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq, Default)]
+pub struct Encryption {
+	/// Cipher passed to `cryptsetup luksFormat --cipher`, cryptsetup's default if not set
+	#[serde(default)]
+	pub cipher: Option<String>,
+	/// Path to a key file, relative to the manifest
+	#[serde(default)]
+	pub key_file: Option<PathBuf>,
+	/// Name of an environment variable holding the passphrase, used if `key_file` is not set
+	#[serde(default)]
+	pub passphrase_env: Option<String>,
+	/// Name of the unlocked device in `/etc/crypttab`, defaults to `luks-<UUID>`
+	#[serde(default)]
+	pub name: Option<String>,
+	/// Let systemd-cryptsetup unlock with a TPM2 chip, which has to be enrolled on first boot
+	#[serde(default)]
+	pub tpm2: bool,
+	/// Let systemd-cryptsetup unlock with a FIDO2 token, which has to be enrolled on first boot
+	#[serde(default)]
+	pub fido2: bool,
+}
+
+impl Encryption {
+	/// The key file contents or passphrase used to unlock the partition
+	fn key(&self) -> Result<Vec<u8>> {
+		if let Some(key_file) = &self.key_file {
+			return Ok(fs::read(key_file)?);
+		}
+		bail_let!(Some(var) = &self.passphrase_env => "Encrypted partitions need either `key_file` or `passphrase_env`");
+		std::env::var(var)
+			.map(String::into_bytes)
+			.map_err(|_| color_eyre::eyre::eyre!("LUKS passphrase variable `{var}` is not set"))
+	}
+
+	/// Runs cryptsetup, passing the key through stdin so it never shows up in the process list
+	fn cryptsetup(&self, args: &[&str]) -> Result<()> {
+		use std::process::{Command, Stdio};
+		trace!(?args, "cryptsetup");
+		let mut child = Command::new("cryptsetup")
+			.arg("--key-file=-")
+			.args(args)
+			.stdin(Stdio::piped())
+			.spawn()?;
+		bail_let!(Some(mut stdin) = child.stdin.take() => "Cannot open stdin of cryptsetup");
+		stdin.write_all(&self.key()?)?;
+		drop(stdin);
+		let status = child.wait()?;
+		if !status.success() {
+			bail!("cryptsetup {} failed: {status}", args.join(" "));
+		}
+		Ok(())
+	}
+
+	/// Formats `devname` as LUKS2, then opens it as `/dev/mapper/<mapper>`
+	fn format(&self, devname: &str, mapper: &str) -> Result<()> {
+		info!(devname, "Encrypting partition");
+		let mut args = vec!["luksFormat", "--type", "luks2", "--batch-mode"];
+		if let Some(cipher) = &self.cipher {
+			args.extend(["--cipher", cipher]);
+		}
+		args.push(devname);
+		self.cryptsetup(&args)?;
+		self.cryptsetup(&["open", devname, mapper])
+	}
+}
+
+impl Partition {
+	/// MBR partition type code, FAT32 (LBA) for `efi` partitions as most boot ROMs and
+	/// UEFI firmware accept it, unless `mbr_type` says otherwise
+	pub fn mbr_type(&self) -> u8 {
+		self.mbr_type.unwrap_or(match self.filesystem.as_str() {
+			"efi" | "vfat" | "fat32" => 0x0c,
+			"swap" => 0x82,
+			"lvm" => 0x8e,
+			_ => 0x83,
+		})
+	}
+
+	pub fn mbr_bootable(&self) -> bool {
+		self.bootable.unwrap_or(self.filesystem == "efi")
+	}
+
+	/// Creates the btrfs subvolumes on a freshly formatted partition
+	fn create_subvolumes(&self, devname: &str) -> Result<()> {
+		let tmp = Path::new("/tmp/katsu.btrfs");
+		std::fs::create_dir_all(tmp)?;
+		cmd_lib::run_cmd!(mount $devname $tmp 2>&1)?;
+
+		let res = (|| -> Result<()> {
+			for subvol in &self.subvolumes {
+				let path = tmp.join(subvol.name.trim_start_matches('/'));
+				if let Some(parent) = path.parent() {
+					std::fs::create_dir_all(parent)?;
+				}
+				debug!(name = subvol.name, "Creating btrfs subvolume");
+				cmd_lib::run_cmd!(btrfs subvolume create $path 2>&1)?;
+			}
+
+			if let Some(default) = &self.default_subvolume {
+				let path = tmp.join(default.trim_start_matches('/'));
+				debug!(default, "Setting default btrfs subvolume");
+				cmd_lib::run_cmd!(btrfs subvolume set-default $path 2>&1)?;
+			}
+			Ok(())
+		})();
+
+		cmd_lib::run_cmd!(umount $tmp 2>&1)?;
+		res
+	}
 }
 
 #[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
 pub struct BtrfsSubvolume {
+	/// Path of the subvolume relative to the top-level subvolume, e.g. `@home`
 	pub name: String,
+	/// Where to mount the subvolume, use `-` to not mount it
 	pub mountpoint: String,
 }
 
+#[test]
+fn test_mount_entries_subvolumes() {
+	let partlay: PartitionLayout = serde_yaml::from_str(
+		r#"
+partitions:
+  - type: esp
+    size: 512MiB
+    filesystem: efi
+    mountpoint: /boot/efi
+  - type: root
+    filesystem: btrfs
+    mountpoint: "-"
+    compression: zstd:1
+    default_subvolume: "@"
+    subvolumes:
+      - name: "@home"
+        mountpoint: /home
+      - name: "@"
+        mountpoint: /
+      - name: "@snapshots"
+        mountpoint: "-"
+"#,
+	)
+	.unwrap();
+
+	let entries = partlay
+		.mount_entries()
+		.into_iter()
+		.map(|e| (e.source, e.mountpoint, e.options.join(",")))
+		.collect::<Vec<_>>();
+
+	assert_eq!(
+		entries,
+		vec![
+			(MountSource::Partition(2), "/", "subvol=@,compress=zstd:1".to_string()),
+			(MountSource::Partition(2), "/home", "subvol=@home,compress=zstd:1".to_string()),
+			(MountSource::Partition(1), "/boot/efi", String::new()),
+		]
+	);
+}
+
+#[test]
+fn test_fs_device_encrypted() {
+	let partlay: PartitionLayout = serde_yaml::from_str(
+		r#"
+partitions:
+  - type: esp
+    size: 512MiB
+    filesystem: efi
+    mountpoint: /boot/efi
+  - type: root
+    filesystem: ext4
+    mountpoint: /
+    encryption:
+      key_file: /dev/null
+      tpm2: true
+"#,
+	)
+	.unwrap();
+
+	let disk = Path::new("/dev/loop0");
+	assert_eq!(partlay.fs_device(disk, 1), "/dev/loop0p1");
+	assert_eq!(partlay.fs_device(disk, 2), "/dev/mapper/katsu-luks2");
+}
+
+#[test]
+fn test_mount_entries_lvm() {
+	let partlay: PartitionLayout = serde_yaml::from_str(
+		r#"
+partitions:
+  - type: esp
+    size: 512MiB
+    filesystem: efi
+    mountpoint: /boot/efi
+  - type: linux-lvm
+    filesystem: lvm
+    mountpoint: "-"
+    volume_group: system
+lvm:
+  - name: system
+    volumes:
+      - name: home
+        percent_free: 50
+        filesystem: xfs
+        mountpoint: /home
+      - name: root
+        size: 4GiB
+        filesystem: ext4
+        mountpoint: /
+"#,
+	)
+	.unwrap();
+
+	partlay.validate_lvm().unwrap();
+
+	let entries =
+		partlay.mount_entries().into_iter().map(|e| (e.source, e.mountpoint)).collect::<Vec<_>>();
+	assert_eq!(
+		entries,
+		vec![
+			(MountSource::LogicalVolume { vg: "system", lv: "root" }, "/"),
+			(MountSource::LogicalVolume { vg: "system", lv: "home" }, "/home"),
+			(MountSource::Partition(1), "/boot/efi"),
+		]
+	);
+
+	let volumes = &partlay.lvm[0].volumes;
+	assert_eq!(volumes[0].size_args().unwrap(), ["-l", "50%FREE"]);
+	assert_eq!(volumes[1].size_args().unwrap(), ["-L", "4294967296b"]);
+}
+
+#[test]
+fn test_fstab_entries() {
+	let partlay: PartitionLayout = serde_yaml::from_str(
+		r#"
+partitions:
+  - type: esp
+    size: 512MiB
+    filesystem: efi
+    mountpoint: /boot/efi
+    mount_options: [umask=0077]
+  - type: swap
+    size: 1GiB
+    filesystem: swap
+    mountpoint: "-"
+  - type: linux-generic
+    size: 1GiB
+    filesystem: ext4
+    mountpoint: /srv
+    fstab: false
+  - type: root
+    filesystem: xfs
+    mountpoint: /
+    fsck_pass: 1
+    mount_by: partuuid
+"#,
+	)
+	.unwrap();
+
+	let entries = partlay
+		.fstab_entries()
+		.into_iter()
+		.map(|e| (e.mountpoint, e.fstype(), e.fstab_options(), e.fsck_pass()))
+		.collect::<Vec<_>>();
+
+	assert_eq!(
+		entries,
+		vec![
+			("/", "xfs", "defaults".to_string(), 1),
+			("/boot/efi", "vfat", "umask=0077".to_string(), 0),
+			("none", "swap", "defaults".to_string(), 0),
+		]
+	);
+	assert_eq!(partlay.partitions[3].mount.mount_by, Some(MountBy::PartUuid));
+	assert_eq!(partlay.mount_by, MountBy::Uuid);
+}
+
+#[test]
+fn test_partition_bounds() {
+	let mut partlay: PartitionLayout = serde_yaml::from_str(
+		r#"
+partitions:
+  - type: esp
+    size: 512MiB
+    filesystem: efi
+    mountpoint: /boot/efi
+  - type: swap
+    size: 25%
+    filesystem: swap
+    mountpoint: "-"
+  - type: root
+    filesystem: ext4
+    mountpoint: /
+"#,
+	)
+	.unwrap();
+	const MIB: u64 = 1024 * 1024;
+
+	let bounds = partlay.partition_bounds(8192 * MIB, DiskLabel::Gpt).unwrap();
+	assert_eq!(bounds, [(MIB, 513 * MIB), (513 * MIB, 2561 * MIB), (2561 * MIB, 8191 * MIB)]);
+	assert!(partlay.partition_bounds(600 * MIB, DiskLabel::Gpt).is_err());
+	assert!(partlay.auto_size(DiskLabel::Gpt, 4000 * MIB).is_err());
+
+	partlay.alignment = Some(ByteSize::kib(4));
+	partlay.partitions[1].size = Some(PartitionSize::Sectors(2048));
+	let bounds = partlay.partition_bounds(8192 * MIB, DiskLabel::Msdos).unwrap();
+	assert_eq!(bounds[0], (4096, 4096 + 512 * MIB));
+	assert_eq!(bounds[2].1, 8192 * MIB);
+
+	// 4000 MiB with 25% headroom
+	let size = partlay.auto_size(DiskLabel::Gpt, 4000 * MIB).unwrap();
+	assert_eq!(size, 512 * MIB + MIB + 5000 * MIB + 40960);
+	let bounds = partlay.partition_bounds(size, DiskLabel::Gpt).unwrap();
+	assert_eq!(bounds[2].1 - bounds[2].0, 5000 * MIB);
+
+	partlay.partitions[1].size = None;
+	assert!(partlay.partition_bounds(8192 * MIB, DiskLabel::Gpt).is_err());
+}
+
+#[test]
+fn test_auto_size_lvm() {
+	let partlay: PartitionLayout = serde_yaml::from_str(
+		r#"
+size: auto
+partitions:
+  - type: esp
+    size: 512MiB
+    filesystem: efi
+    mountpoint: /boot/efi
+  - type: linux-lvm
+    filesystem: lvm
+    mountpoint: "-"
+    volume_group: katsu
+lvm:
+  - name: katsu
+    volumes:
+      - name: root
+        size: 4GiB
+        filesystem: ext4
+        mountpoint: /
+      - name: home
+        filesystem: xfs
+        mountpoint: /home
+"#,
+	)
+	.unwrap();
+	const MIB: u64 = 1024 * 1024;
+
+	// 1000 MiB outside of the root volume with 25% headroom, the root volume and 3 extents
+	let size = partlay.auto_size(DiskLabel::Gpt, 1000 * MIB).unwrap();
+	assert_eq!(size, MIB + 512 * MIB + (1250 + 4096 + 12) * MIB + MIB);
+}
+
+#[test]
+fn test_hybrid_mbr_partitions() {
+	let mut partlay: PartitionLayout = serde_yaml::from_str(
+		r#"
+label: hybrid
+partitions:
+  - type: esp
+    size: 512MiB
+    filesystem: efi
+    mountpoint: /boot/efi
+    mbr_type: 0x0c
+  - type: xbootldr
+    size: 1GiB
+    filesystem: ext4
+    mountpoint: /boot
+  - type: root
+    filesystem: ext4
+    mountpoint: /
+    mbr_type: 0x83
+    bootable: true
+"#,
+	)
+	.unwrap();
+
+	assert_eq!(partlay.label, Some(DiskLabel::Hybrid));
+	assert_eq!(partlay.hybrid_mbr_partitions().unwrap(), [(1, 0x0c, true), (3, 0x83, true)]);
+	assert_eq!(partlay.partitions[1].mbr_type(), 0x83);
+	assert!(!partlay.partitions[1].mbr_bootable());
+
+	partlay.partitions.iter_mut().for_each(|p| p.mbr_type = None);
+	assert!(partlay.hybrid_mbr_partitions().is_err());
+}
+
+#[test]
+fn test_add_verity_partitions() {
+	let mut partlay: PartitionLayout = serde_yaml::from_str(
+		r#"
+partitions:
+  - type: esp
+    size: 512MiB
+    filesystem: efi
+    mountpoint: /boot/efi
+  - label: root
+    type: root
+    size: 4GiB
+    filesystem: ext4
+    mountpoint: /
+    verity: true
+  - type: var
+    filesystem: ext4
+    mountpoint: /var
+"#,
+	)
+	.unwrap();
+
+	partlay.add_verity_partitions().unwrap();
+
+	let hash = &partlay.partitions[2];
+	assert_eq!(hash.partition_type, PartitionType::RootVerity);
+	assert_eq!(hash.label.as_deref(), Some("root-verity"));
+	// 4 GiB of data need 32 MiB and a bit of hashes
+	assert_eq!(hash.size, Some(PartitionSize::Bytes(ByteSize::mib(33))));
+	assert_eq!(partlay.partitions[3].mountpoint, "/var");
+	assert_eq!(partlay.partitions[1].mount.mount_options, ["ro"]);
+	assert_eq!(partlay.partitions[1].flags, Some(vec![PartitionFlag::ReadOnly]));
+
+	partlay.partitions[3].verity = true;
+	assert!(partlay.add_verity_partitions().is_err());
+}
+
 #[test]
 fn test_bytesize() {
 	use std::str::FromStr;
@@ -1,5 +1,10 @@
+use crate::config::Manifest;
 use color_eyre::Result;
-use std::{fs::File, path::Path};
+use std::{
+	fs::File,
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
 use tracing::{debug, error};
 
 #[macro_export]
@@ -163,7 +168,50 @@ macro_rules! prepend_comment {
 	};
 }
 
+/// Template override directory and the manifest exposed to templates as `manifest`
+pub struct Templates {
+	dir: Option<PathBuf>,
+	manifest: tera::Value,
+}
+
+impl Templates {
+	pub fn new(manifest: &Manifest) -> Result<Self> {
+		Ok(Self { dir: manifest.templates.clone(), manifest: tera::to_value(manifest)? })
+	}
+
+	/// Returns the source of the template `name`, from the override directory if it has one,
+	/// and adds the manifest to `ctx`
+	pub fn source(&self, name: &str, builtin: &str, ctx: &mut tera::Context) -> Result<String> {
+		ctx.insert("manifest", &self.manifest);
+		if let Some(path) = self.dir.as_ref().map(|d| d.join(name)).filter(|p| p.exists()) {
+			debug!(?path, "Using template override");
+			return Ok(std::fs::read_to_string(path)?);
+		}
+		Ok(builtin.to_string())
+	}
+}
+
+/// The templates of the manifest being built, set once by [`set_templates`]
+static TEMPLATES: Mutex<Option<Templates>> = Mutex::new(None);
+
+/// Makes [`tpl!`] prefer templates in `manifest.templates` and expose `manifest` to them
+pub fn set_templates(manifest: &Manifest) -> Result<()> {
+	*TEMPLATES.lock().unwrap() = Some(Templates::new(manifest)?);
+	Ok(())
+}
+
+/// [`Templates::source`] for the manifest being built, the built-in template before
+/// [`set_templates`] is called
+pub fn template_source(name: &str, builtin: &str, ctx: &mut tera::Context) -> Result<String> {
+	match &*TEMPLATES.lock().unwrap() {
+		Some(templates) => templates.source(name, builtin, ctx),
+		None => Ok(builtin.to_string()),
+	}
+}
+
 /// Generates the file content using the template given
+///
+/// Templates in the manifest's `templates` directory take precedence over the built-in ones.
 #[macro_export]
 macro_rules! tpl {
 	(@match $name:ident) => {
@@ -179,7 +227,12 @@ macro_rules! tpl {
 		$(
 			ctx.insert(stringify!($name), &$crate::tpl!(@match $name$(: $var)?));
 		)*
-		let out = tera.render_str(include_str!(concat!("../templates/", $tmpl)), &ctx)?;
+		let src = $crate::util::template_source(
+			$tmpl,
+			include_str!(concat!("../templates/", $tmpl)),
+			&mut ctx,
+		)?;
+		let out = tera.render_str(&src, &ctx)?;
 		tracing::trace!(out, path = $tmpl, "tpl!() Template output");
 		$(
 			tracing::debug!(tmpl=?$tmpl, outfile=?$out, "Writing template output to file");
@@ -425,3 +478,171 @@ pub fn just_write(path: impl AsRef<Path>, content: impl AsRef<str>) -> Result<()
 	File::create(path)?.write_all(content.as_bytes())?;
 	Ok(())
 }
+
+/// Recursively sums the apparent size of all files under `path`
+pub fn dir_size(path: &Path) -> Result<u64> {
+	let meta = std::fs::symlink_metadata(path)?;
+	if !meta.is_dir() {
+		return Ok(meta.len());
+	}
+	std::fs::read_dir(path)?.try_fold(0, |acc, entry| Ok(acc + dir_size(&entry?.path())?))
+}
+
+/// What [`ensure_safe_to_wipe`] lets through, from `--force` and `--yes`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WipePolicy {
+	/// Allow wiping devices which are not removable
+	pub force: bool,
+	/// Do not ask for confirmation
+	pub yes: bool,
+}
+
+/// Makes sure `disk` is safe to wipe before we repartition it
+///
+/// Devices which are mounted or hold the host's `/` or `/boot` are always refused.
+/// Non-removable devices are refused unless `wipe.force` is set, and real devices
+/// need confirmation, either interactively or with `wipe.yes`.
+///
+/// Loop devices backed by image files only get the first check, the ones backed by a block
+/// device get all of them for that device.
+pub fn ensure_safe_to_wipe(disk: &Path, wipe: WipePolicy) -> Result<()> {
+	use std::{
+		io::{BufRead, IsTerminal, Write},
+		os::unix::fs::FileTypeExt,
+	};
+
+	let disk = disk.canonicalize()?;
+	crate::bail_let!(Some(name) = disk.file_name().and_then(|n| n.to_str()) => "Invalid device path: {disk:?}");
+	let is_loop = name.starts_with("loop");
+
+	// lsblk also lists partitions and holders (LVM, LUKS) of the device
+	let mounted = cmd_lib::run_fun!(lsblk -nrpo MOUNTPOINT $disk)?;
+	if let Some(mp) = mounted.lines().find(|l| !l.trim().is_empty()) {
+		color_eyre::eyre::bail!(
+			"Refusing to wipe {disk:?}, it (or one of its partitions) is mounted at {mp}"
+		);
+	}
+
+	for host_mp in ["/", "/boot"] {
+		let Ok(source) = cmd_lib::run_fun!(findmnt -nvo SOURCE $host_mp) else { continue };
+		let Ok(ancestors) = cmd_lib::run_fun!(lsblk -nspro NAME $source) else { continue };
+		if ancestors.lines().any(|l| Path::new(l.trim()) == disk) {
+			color_eyre::eyre::bail!("Refusing to wipe {disk:?}, it holds the host's {host_mp}");
+		}
+	}
+
+	if is_loop {
+		let back = cmd_lib::run_fun!(losetup -nO BACK-FILE $disk)?;
+		let back = Path::new(back.trim());
+		if std::fs::metadata(back).is_ok_and(|m| m.file_type().is_block_device()) {
+			tracing::debug!(?disk, ?back, "Loop device is backed by a block device");
+			return ensure_safe_to_wipe(back, wipe);
+		}
+		return Ok(());
+	}
+
+	let removable = cmd_lib::run_fun!(lsblk -dno RM,HOTPLUG $disk)?;
+	if !removable.split_whitespace().any(|f| f == "1") {
+		if !wipe.force {
+			color_eyre::eyre::bail!("Refusing to wipe {disk:?}, it is not a removable device. Use --force if you really mean it");
+		}
+		tracing::warn!(?disk, "Device is not removable, continuing anyway because of --force");
+	}
+
+	let info = cmd_lib::run_fun!(lsblk -o NAME,MODEL,SIZE,TYPE,FSTYPE,LABEL $disk)?;
+	tracing::warn!("All data on {disk:?} will be erased:\n{info}");
+
+	if wipe.yes {
+		return Ok(());
+	}
+
+	let mut stdin = std::io::stdin().lock();
+	if !stdin.is_terminal() {
+		color_eyre::eyre::bail!("Refusing to wipe {disk:?} without confirmation, use --yes for non-interactive installs");
+	}
+	print!("Type 'yes' to continue: ");
+	std::io::stdout().flush()?;
+	let mut answer = String::new();
+	stdin.read_line(&mut answer)?;
+	if answer.trim() != "yes" {
+		color_eyre::eyre::bail!("Aborted by user");
+	}
+
+	Ok(())
+}
+
+/// `SOURCE_DATE_EPOCH` for reproducible builds, if set
+pub fn source_date_epoch() -> Option<u64> {
+	std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|s| s.parse().ok())
+}
+
+/// Today's date in UTC as `YYYY-MM-DD`, honoring `SOURCE_DATE_EPOCH` for reproducible builds
+pub fn today() -> String {
+	let secs = source_date_epoch().unwrap_or_else(|| {
+		std::time::SystemTime::now()
+			.duration_since(std::time::UNIX_EPOCH)
+			.map_or(0, |d| d.as_secs())
+	});
+	let (y, m, d) = civil_from_days((secs / 86400) as i64);
+	format!("{y:04}-{m:02}-{d:02}")
+}
+
+/// `secs` since the Unix epoch in UTC as formatted by `ctime(3)`, i.e. `%c` in the C locale
+pub fn ctime(secs: u64) -> String {
+	const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+	const MONTHS: [&str; 12] =
+		["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+	let days = secs / 86400;
+	let (y, m, d) = civil_from_days(days as i64);
+	let (h, min, s) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+	let (wday, month) = (DAYS[(days % 7) as usize], MONTHS[m as usize - 1]);
+	format!("{wday} {month} {d:2} {h:02}:{min:02}:{s:02} {y}")
+}
+
+#[test]
+fn test_ctime() {
+	assert_eq!(ctime(0), "Thu Jan  1 00:00:00 1970");
+	assert_eq!(ctime(1704067200 + 3723), "Mon Jan  1 01:02:03 2024");
+	assert_eq!(ctime(951782400), "Tue Feb 29 00:00:00 2000");
+}
+
+/// Converts days since the Unix epoch to a `(year, month, day)` date
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+	let z = z + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z.rem_euclid(146097);
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+	let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+	(yoe + era * 400 + i64::from(m <= 2), m, d)
+}
+
+#[test]
+fn test_civil_from_days() {
+	assert_eq!(civil_from_days(0), (1970, 1, 1));
+	assert_eq!(civil_from_days(11016), (2000, 2, 29));
+	assert_eq!(civil_from_days(19723), (2024, 1, 1));
+}
+
+#[test]
+fn test_template_override() {
+	let dir = std::env::temp_dir().join("katsu-test-templates");
+	std::fs::create_dir_all(&dir).unwrap();
+	std::fs::write(dir.join("katsu-test.tera"), "{{ manifest.distro }} {{ name }}").unwrap();
+	let manifest: Manifest =
+		serde_yaml::from_str(&format!("distro: Ultramarine Linux\ntemplates: {}\n", dir.display()))
+			.unwrap();
+	// not set_templates, tests calling tpl! in parallel would render with it
+	let templates = Templates::new(&manifest).unwrap();
+
+	let mut ctx = tera::Context::new();
+	ctx.insert("name", "katsu");
+	let src = templates.source("katsu-test.tera", "{{ name }}", &mut ctx).unwrap();
+	let out = tera::Tera::default().render_str(&src, &ctx).unwrap();
+	assert_eq!(out, "Ultramarine Linux katsu");
+	assert_eq!(templates.source("fstab.tera", "builtin", &mut ctx).unwrap(), "builtin");
+	std::fs::remove_dir_all(dir).unwrap();
+}